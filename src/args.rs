@@ -1,8 +1,11 @@
 use std::path::PathBuf;
 
-use clap::{command, Parser};
+use anyhow::bail;
+use clap::{Parser, Subcommand, ValueEnum};
 use pallas::network::miniprotocols::Point;
 
+use crate::{network::Network, storage::{Layout, Storage}};
+
 #[derive(Parser)]
 #[command(author, version, about)]
 pub struct Args {
@@ -14,26 +17,315 @@ pub struct Args {
     #[arg(short, long)]
     pub topology_file: Option<PathBuf>,
 
+    /// The local socket of a cardano-node to slurp from, using the node-to-client protocols, instead of any relays
+    #[arg(short, long, conflicts_with_all = ["relay", "topology_file", "max_peers"])]
+    pub socket: Option<PathBuf>,
+
     /// The point to start initially syncronizing from, if there are no cursor files
+    ///
+    /// One of `origin`, `slot/hash`, a slot number we've already stored a header for, `epoch:N`
+    /// (from the start of its era, unless we've stored the header just before it), or the name of an era
+    /// to start from (byron, shelley, allegra, mary, alonzo, babbage)
     #[arg(short, long, value_parser = parse_point)]
-    pub fallback_point: Option<Point>,
+    pub fallback_point: Option<PointSpec>,
 
     /// The directory to save blocks into
-    #[arg(short, long, default_value = "db")]
+    #[arg(short, long, default_value = "db", global = true)]
     pub directory: PathBuf,
 
     /// The network magic to use when communicating with nodes
+    #[arg(long, global = true)]
+    pub testnet_magic: Option<u64>,
+
+    /// How to lay out a new archive on disk; an existing archive keeps the layout it has (see `migrate`)
+    #[arg(long, value_enum, global = true)]
+    pub layout: Option<Layout>,
+
+    /// Index every transaction by hash as blocks are saved, recording which block it's in
+    ///
+    /// Once used, the archive remembers to keep doing so, including when it's reindexed
+    #[arg(long, global = true)]
+    pub index_transactions: bool,
+
+    /// Save each transaction in the blocks we slurp on its own too, under `txs`
+    ///
+    /// Once used, the archive remembers to keep doing so
+    #[arg(long, global = true)]
+    pub save_transactions: bool,
+
+    /// Serve the archive over HTTP on this address, such as 127.0.0.1:8080, while slurping into it
+    #[arg(long)]
+    pub http: Option<String>,
+
+    /// Serve the archive to other nodes over the node-to-node protocol on this address, such as 0.0.0.0:3001
+    ///
+    /// Peers can sync headers and fetch blocks from us with chain-sync and block-fetch, just as from a relay
+    #[arg(long)]
+    pub serve: Option<String>,
+
+    /// Stream chain events, each block we save and each rollback, to stdout as newline-delimited JSON
+    #[arg(long)]
+    pub events_stdout: bool,
+
+    /// Replay the blocks after this point on the archive's chain to stdout, before streaming new events
+    ///
+    /// Takes the same points as `--fallback-point`
+    #[arg(long, value_parser = parse_point, requires = "events_stdout")]
+    pub events_from: Option<PointSpec>,
+
+    /// Stream chain events over WebSockets on this address, such as 127.0.0.1:8081
+    ///
+    /// Clients can connect with `?from=` and a point to replay from, just as with `--events-from`
+    #[arg(long)]
+    pub events_websocket: Option<String>,
+
+    /// Publish every block and rollback to NATS JetStream at this url, such as nats://127.0.0.1:4222
+    ///
+    /// Each is acknowledged by the broker before the cursors advance past it, so nothing is skipped across restarts.
+    /// Use a tls:// url to insist on TLS, which is also used whenever the server requires it
+    #[arg(long)]
+    pub nats: Option<String>,
+
+    /// The subject to publish to with `--nats`, with `.blocks` or `.rollbacks` on the end
+    #[arg(long, default_value = "cardano", requires = "nats")]
+    pub nats_subject: String,
+
+    /// POST a JSON notification to this url when a relay reaches its tip, rolls back too far, stalls or disconnects
+    ///
+    /// Can be given more than once, to notify each of them
     #[arg(long)]
-    pub testnet_magic: Option<u64>
+    pub webhook: Vec<String>,
+
+    /// Notify webhooks of rollbacks deeper than this many blocks
+    #[arg(long, default_value_t = 2)]
+    pub webhook_rollback_depth: u64,
+
+    /// Notify webhooks when a relay hasn't given us a new block in this many seconds
+    #[arg(long, default_value_t = 600)]
+    pub webhook_stall_seconds: u64,
+
+    /// The most peers to slurp from at once
+    ///
+    /// When set, peers are scored on how reliably and quickly they serve us blocks, and the best are kept,
+    /// periodically swapping the worst for another candidate from the relays or topology file (including warm peers)
+    #[arg(long)]
+    pub max_peers: Option<usize>,
+
+    /// How often to send each relay a keep-alive, in seconds, so it doesn't drop the connection while we're idle at
+    /// its tip
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub keepalive_seconds: u64,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
 
-fn parse_point(s: &str) -> Result<Point, String> {
+/// Things to do with the archive other than slurping into it from the network
+#[derive(Subcommand)]
+pub enum Command {
+    /// Import blocks from the ImmutableDB of a cardano-node, rather than downloading them
+    ///
+    /// Blocks are saved just as if they had been slurped, and the archive frontier advanced past them,
+    /// so that syncing from the network afterwards picks up where the import left off
+    Import {
+        /// The node's `immutable` directory, holding its chunk files
+        immutable_directory: PathBuf,
+    },
+    /// Export the chain ending at the newest block in the archive, for use elsewhere
+    Export {
+        /// The format to export to
+        #[arg(long, value_enum)]
+        format: ExportFormat,
+
+        /// The directory to write the export into
+        output_directory: PathBuf,
+    },
+    /// Move the archive over to a different layout on disk
+    Migrate {
+        /// The layout to move to
+        #[arg(long, value_enum)]
+        to: Layout,
+    },
+    /// Train a compression dictionary for each era from the archive, and compress every body with them
+    ///
+    /// Once done, bodies slurped from then on are compressed too. A table of how well each era compressed,
+    /// and how quickly, is logged at the end.
+    Recompress {
+        /// The zstd compression level, from 1 (fastest) to 22 (smallest)
+        #[arg(long, default_value_t = 9, value_parser = clap::value_parser!(i32).range(1..=22))]
+        level: i32,
+
+        /// Roughly how many blocks to sample from across the archive, to train the dictionaries with
+        #[arg(long, default_value_t = 10_000)]
+        samples: usize,
+
+        /// Measure how well the archive would compress, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Rebuild the index of the archive from scratch, from every header and body in it
+    ///
+    /// The index is kept up to date as blocks are slurped, so this is only needed if it's been lost, or
+    /// has fallen behind after a crash.
+    Reindex,
+
+    /// Look up which block a transaction is in, by its hash
+    ///
+    /// Prints the point of the block and the transaction's position in it. Only works for archives indexed
+    /// with `--index-transactions`.
+    Transaction {
+        /// The hash of the transaction, in hex
+        hash: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    /// The chunk and index files of a cardano-node's ImmutableDB, which a node can bootstrap from
+    #[value(name = "immutabledb")]
+    ImmutableDb,
+}
+
+/// A point as given on the command line, which may need the local archive or network parameters to resolve
+#[derive(Clone, Debug)]
+pub enum PointSpec {
+    /// `origin`, or an explicit `slot/hash`
+    Point(Point),
+    /// A bare slot number, whose hash we look up from the headers we've stored
+    Slot(u64),
+    /// `epoch:N`, the start of an epoch
+    Epoch(u64),
+    /// The start of a named era, such as `shelley` or `babbage`
+    Era(String),
+}
+
+const ERAS: &[&str] = &["byron", "shelley", "allegra", "mary", "alonzo", "babbage"];
+
+pub fn parse_point(s: &str) -> Result<PointSpec, String> {
+  let s = s.trim();
   if s == "origin" {
-    Ok(Point::Origin)
-  } else { 
-    let parts: Vec<_> = s.split("/").collect();
-    let (slot, block) = (parts[0], parts[1]);
-    let (slot, block) = (slot.parse::<u64>().unwrap(), hex::decode(block).unwrap());
-    Ok(Point::Specific(slot, block))
-  } 
-}
\ No newline at end of file
+    Ok(PointSpec::Point(Point::Origin))
+  } else if let Some((slot, hash)) = s.split_once('/') {
+    let slot = slot
+      .parse::<u64>()
+      .map_err(|_| format!("'{}' is not a valid slot number", slot))?;
+    let hash = hex::decode(hash).map_err(|e| format!("'{}' is not a valid hex block hash: {}", hash, e))?;
+    if hash.len() != 32 {
+      return Err(format!("block hashes are 32 bytes, but '{}' is {} bytes", hex::encode(&hash), hash.len()));
+    }
+    Ok(PointSpec::Point(Point::Specific(slot, hash)))
+  } else if let Some(epoch) = s.strip_prefix("epoch:") {
+    let epoch = epoch
+      .parse::<u64>()
+      .map_err(|_| format!("'{}' is not a valid epoch number", epoch))?;
+    Ok(PointSpec::Epoch(epoch))
+  } else if let Ok(slot) = s.parse::<u64>() {
+    Ok(PointSpec::Slot(slot))
+  } else if ERAS.contains(&s) {
+    Ok(PointSpec::Era(s.to_string()))
+  } else {
+    Err(format!(
+      "expected 'origin', 'slot/hash', a slot number, 'epoch:N', or one of {}",
+      ERAS.join(", ")
+    ))
+  }
+}
+
+impl PointSpec {
+  /// Resolve to a concrete point, using the headers we've already stored and the parameters of the network
+  pub fn resolve(&self, headers: &dyn Storage, magic: u64) -> anyhow::Result<Point> {
+    match self {
+      PointSpec::Point(point) => Ok(point.clone()),
+      PointSpec::Slot(slot) => match headers.before(*slot) {
+        Some(point) if point.slot_or_default() == *slot => Ok(point),
+        _ => bail!("no header stored at slot {}, so its hash isn't known; try slot/hash instead", slot),
+      },
+      PointSpec::Epoch(epoch) => {
+        let Some(network) = Network::from_magic(magic) else {
+          bail!("epoch boundaries aren't known for network magic {}", magic);
+        };
+        let start = network.epoch_start_slot(*epoch);
+        if start == 0 {
+          return Ok(Point::Origin);
+        }
+        // Intersecting with the last block of the previous epoch means we'll sync from the first block of this one,
+        // but we only know its hash if we've stored it; otherwise, the best we can do is the start of the epoch's era
+        let stored = headers.before(start - 1);
+        if stored.as_ref().is_some_and(|point| network.slot_epoch(point.slot_or_default()) + 1 == *epoch) {
+          return Ok(stored.unwrap());
+        }
+        let era = network.boundaries.iter().rev().find(|b| b.slot < start).map_or("byron", |b| b.era);
+        let era_start = network.era_start(era).expect("era boundaries are known");
+        let point = match stored {
+          Some(stored) if stored.slot_or_default() > era_start.slot_or_default() => stored,
+          _ => era_start,
+        };
+        log::warn!("no header stored just before epoch {}, so starting from {:?} instead, the nearest point before it we know of", epoch, point);
+        Ok(point)
+      }
+      PointSpec::Era(era) => {
+        let Some(network) = Network::from_magic(magic) else {
+          bail!("era boundaries aren't known for network magic {}", magic);
+        };
+        match network.era_start(era) {
+          Some(point) => Ok(point),
+          None => bail!("the {} era has no known starting point on {}", era, network.name),
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pallas::network::miniprotocols::{MAINNET_MAGIC, PREVIEW_MAGIC};
+
+  use super::*;
+  use crate::{storage::Archive, testing::TempDir};
+
+  fn resolve(spec: &str, headers: &dyn Storage, magic: u64) -> anyhow::Result<Point> {
+    parse_point(spec).map_err(|e| anyhow::anyhow!(e))?.resolve(headers, magic)
+  }
+
+  #[test]
+  fn points_are_parsed_in_each_form() {
+    let hash = "f85c52e97c6ec4e171d92789e32331e624ee7a0c7ba18b578062727edb7d61f7";
+    assert!(matches!(parse_point("origin"), Ok(PointSpec::Point(Point::Origin))));
+    assert!(matches!(parse_point(&format!("78416/{}", hash)), Ok(PointSpec::Point(Point::Specific(78416, _)))));
+    assert!(matches!(parse_point(" 78416 "), Ok(PointSpec::Slot(78416))));
+    assert!(matches!(parse_point("epoch:208"), Ok(PointSpec::Epoch(208))));
+    assert!(matches!(parse_point("babbage"), Ok(PointSpec::Era(era)) if era == "babbage"));
+
+    assert!(parse_point("78416/abcd").unwrap_err().contains("32 bytes"));
+    assert!(parse_point(&format!("slot/{}", hash)).unwrap_err().contains("not a valid slot number"));
+    assert!(parse_point("epoch:last").unwrap_err().contains("not a valid epoch number"));
+    assert!(parse_point("conway").unwrap_err().starts_with("expected"));
+  }
+
+  #[test]
+  fn points_are_resolved_from_stored_headers_and_network_boundaries() {
+    let directory = TempDir::new("args");
+    let headers = Archive::open_raw(directory.path(), Layout::Files).unwrap().headers;
+    let stored = Point::Specific(21599, vec![1; 32]);
+    headers.write(&stored, &[0x80]).unwrap();
+    let shelley = Network::from_magic(MAINNET_MAGIC).unwrap().era_start("shelley").unwrap();
+
+    assert_eq!(resolve("origin", headers.as_ref(), MAINNET_MAGIC).unwrap(), Point::Origin);
+    assert_eq!(resolve("21599", headers.as_ref(), MAINNET_MAGIC).unwrap(), stored);
+    assert!(resolve("21600", headers.as_ref(), MAINNET_MAGIC).is_err());
+    assert_eq!(resolve("shelley", headers.as_ref(), MAINNET_MAGIC).unwrap(), shelley);
+    assert!(resolve("shelley", headers.as_ref(), PREVIEW_MAGIC).is_err());
+
+    assert_eq!(resolve("epoch:0", headers.as_ref(), MAINNET_MAGIC).unwrap(), Point::Origin);
+    // The header just before the epoch is stored
+    assert_eq!(resolve("epoch:1", headers.as_ref(), MAINNET_MAGIC).unwrap(), stored);
+    // It isn't, but a header from earlier in the era is, which is nearer than the era's start
+    assert_eq!(resolve("epoch:5", headers.as_ref(), MAINNET_MAGIC).unwrap(), stored);
+    // Nothing of the era is stored, so from the start of it
+    assert_eq!(resolve("epoch:208", headers.as_ref(), MAINNET_MAGIC).unwrap(), shelley);
+    let alonzo = Network::from_magic(MAINNET_MAGIC).unwrap().era_start("alonzo").unwrap();
+    assert_eq!(resolve("epoch:300", headers.as_ref(), MAINNET_MAGIC).unwrap(), alonzo);
+    assert!(resolve("epoch:1", headers.as_ref(), 42).is_err());
+  }
+}
@@ -1,7 +1,6 @@
 use std::{
-    fs,
-    path::PathBuf,
-    sync::{mpsc::Receiver, Mutex, Arc},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc::Receiver, Mutex, Arc},
     thread::{self, JoinHandle},
 };
 
@@ -17,29 +16,58 @@ use pallas::{
     },
 };
 
-use ::minicbor::{to_vec as cbor_to_vec};
+use crate::{
+    cursor::{Cursor, FRONTIER_NAME},
+    events::{Event, Events},
+    stats::PeerStats,
+    storage::Storage,
+};
 
-use crate::cursor::{Cursor};
+/// What the header slurp hands on to the body slurp, in the order it followed the chain
+pub enum Batch {
+    /// Fetch the blocks from the first point through to the second
+    Range(Point, Point),
+    /// The relay rolled back to this point, after the ranges before it
+    RollBackward(Point),
+}
 
 pub struct BodySlurp {
     pub directory: PathBuf,
 
+    bodies: Arc<dyn Storage>,
     cursor_mutex: Arc<Mutex<Cursor>>,
+    frontier_mutex: Arc<Mutex<Cursor>>,
+    stats_mutex: Arc<Mutex<PeerStats>>,
+    events: Arc<Events>,
     relay: String,
+    stopping: Arc<AtomicBool>,
     join_handle: Option<JoinHandle<()>>,
 }
 
 impl BodySlurp {
-    pub fn new(relay: String, directory: PathBuf, cursor_mutex: Arc<Mutex<Cursor>>) -> Self {
+    pub fn new(
+        relay: String,
+        directory: PathBuf,
+        bodies: Arc<dyn Storage>,
+        cursor_mutex: Arc<Mutex<Cursor>>,
+        frontier_mutex: Arc<Mutex<Cursor>>,
+        stats_mutex: Arc<Mutex<PeerStats>>,
+        events: Arc<Events>,
+    ) -> Self {
         Self {
             directory,
+            bodies,
             cursor_mutex,
+            frontier_mutex,
+            stats_mutex,
+            events,
             relay,
+            stopping: Arc::new(AtomicBool::new(false)),
             join_handle: None,
         }
     }
 
-    fn ebb_point(cbor: &Vec<u8>) -> Option<Point> {
+    fn ebb_point(cbor: &[u8]) -> Option<Point> {
         type BlockWrapper = (u16, byron::EbBlock);
         let (_, block) = minicbor::decode::<BlockWrapper>(cbor).ok()?;
         let header = block.header;
@@ -49,7 +77,7 @@ impl BodySlurp {
         ))
     }
 
-    fn byron_point(cbor: &Vec<u8>) -> Option<Point> {
+    fn byron_point(cbor: &[u8]) -> Option<Point> {
         type BlockWrapper = (u16, byron::Block);
         let (_, block) = minicbor::decode::<BlockWrapper>(cbor).ok()?;
         let header = block.header;
@@ -59,7 +87,7 @@ impl BodySlurp {
         ))
     }
 
-    fn shelley_or_alonzo_point(cbor: &Vec<u8>) -> Option<Point> {
+    fn shelley_or_alonzo_point(cbor: &[u8]) -> Option<Point> {
         type BlockWrapper = (u16, alonzo::Block);
         let (_, block) = minicbor::decode::<BlockWrapper>(cbor).ok()?;
         let header = block.header;
@@ -69,7 +97,7 @@ impl BodySlurp {
         ))
     }
 
-    fn babbage_point(cbor: &Vec<u8>) -> Option<Point> {
+    fn babbage_point(cbor: &[u8]) -> Option<Point> {
         type BlockWrapper = (u16, babbage::Block);
         let (_, block) = minicbor::decode::<BlockWrapper>(cbor).ok()?;
         let header = block.header;
@@ -79,53 +107,136 @@ impl BodySlurp {
         ))
     }
 
-    fn handle_body(cursor_mutex: Arc<Mutex<Cursor>>, relay: &String, base_directory: &PathBuf, body: Vec<u8>) {
-        let point = BodySlurp::ebb_point(&body)
-            .or_else(|| BodySlurp::byron_point(&body))
-            .or_else(|| BodySlurp::shelley_or_alonzo_point(&body))
-            .or_else(|| BodySlurp::babbage_point(&body))
-            .expect("unrecognized block");
-        log::info!(target: &relay[..11], "downloaded block {:?} ({} bytes)", point, body.len());
+    /// Work out the point a block body belongs at, whichever era it is from
+    pub fn body_point(cbor: &[u8]) -> Option<Point> {
+        BodySlurp::ebb_point(cbor)
+            .or_else(|| BodySlurp::byron_point(cbor))
+            .or_else(|| BodySlurp::shelley_or_alonzo_point(cbor))
+            .or_else(|| BodySlurp::babbage_point(cbor))
+    }
 
-        let path = crate::utils::artifact_path(base_directory.join("bodies"), point.clone());
-        fs::create_dir_all(path.parent().unwrap())
-            .expect(format!("unable to creact directory {:?}", path).as_str());
-        fs::write(path, body).expect("could not save body");
+    /// Save a block body and publish it, then advance the relay's cursor and the archive frontier past it
+    pub fn handle_body(
+        cursor_mutex: Arc<Mutex<Cursor>>,
+        frontier_mutex: Arc<Mutex<Cursor>>,
+        events: &Events,
+        relay: &str,
+        base_directory: &Path,
+        bodies: &dyn Storage,
+        body: Vec<u8>,
+    ) -> Point {
+        let point = BodySlurp::body_point(&body).expect("unrecognized block");
+        log::info!(target: &relay, "downloaded block {:?} ({} bytes)", point, body.len());
+
+        bodies.write(&point, &body).expect("could not save block");
+        // A local node's slurp is never torn down, so there's no giving up on the sinks
+        BodySlurp::advance(cursor_mutex, frontier_mutex, events, &AtomicBool::new(false), relay, base_directory, point.clone(), body);
+        point
+    }
+
+    /// Publish a block body that's been saved, then advance the relay's cursor and the archive frontier past it,
+    /// unless we stopped before it could be published
+    #[allow(clippy::too_many_arguments)]
+    fn advance(
+        cursor_mutex: Arc<Mutex<Cursor>>,
+        frontier_mutex: Arc<Mutex<Cursor>>,
+        events: &Events,
+        stopping: &AtomicBool,
+        relay: &str,
+        base_directory: &Path,
+        point: Point,
+        body: Vec<u8>,
+    ) -> bool {
+        let event = Event::RollForward { relay: relay.to_string(), point: point.clone(), block: body };
+        // Once we're stopping, a new connection to the same peer may already be saving its cursor, so leave it be
+        if !events.publish_until(event, stopping) || stopping.load(Ordering::Relaxed) {
+            return false;
+        }
 
         {
           let mut cursor_gaurd = cursor_mutex.lock().expect("unable to acquire lock");
 
-          cursor_gaurd.add_point(point);
-
-          let cursor_bytes = cbor_to_vec::<&Cursor>(&cursor_gaurd).expect("unable to serialize cursor");
-          fs::write(base_directory.join("cursors").join(relay), cursor_bytes).expect("unable to write cursor file");
+          cursor_gaurd.add_point(point.clone());
+          cursor_gaurd
+              .save(&base_directory.join("cursors").join(relay))
+              .expect("unable to write cursor file");
 
           drop(cursor_gaurd);
         }
-    }
 
-    pub fn slurp(&mut self, channel: StdChannel, block_batches: Receiver<(Point, Point)>) {
-        fs::create_dir_all(&self.directory).expect("couldn't create directory");
+        {
+          let mut frontier_gaurd = frontier_mutex.lock().expect("unable to acquire lock");
 
+          frontier_gaurd.add_frontier_point(point.clone());
+          frontier_gaurd
+              .save(&base_directory.join(FRONTIER_NAME))
+              .expect("unable to write frontier file");
+
+          drop(frontier_gaurd);
+        }
+        true
+    }
+
+    pub fn slurp(&mut self, channel: StdChannel, block_batches: Receiver<Batch>) {
         let directory = self.directory.clone();
+        let bodies = self.bodies.clone();
         let relay = self.relay.clone();
         let cursor = self.cursor_mutex.clone();
+        let frontier = self.frontier_mutex.clone();
+        let stats = self.stats_mutex.clone();
+        let events = self.events.clone();
+        let stopping = self.stopping.clone();
         self.join_handle = Some(thread::spawn(move || {
             let mut client = blockfetch::Client::new(channel);
-            loop {
-                let next_range = block_batches
-                    .recv()
-                    .expect("failed to receive next block range");
-                let blocks = client
-                    .fetch_range(next_range)
-                    .expect("unable to query block range");
-                for block in blocks {
-                    BodySlurp::handle_body(cursor.clone(), &relay, &directory, block);
+            // Once the header slurp stops, there are no more ranges to fetch
+            while let Ok(batch) = block_batches.recv() {
+                let next_range = match batch {
+                    Batch::Range(start, end) => (start, end),
+                    Batch::RollBackward(point) => {
+                        if !events.publish_until(Event::RollBackward { relay: relay.clone(), point }, &stopping) {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                stats.lock().expect("unable to acquire lock").fetching();
+                let blocks = match client.fetch_range(next_range) {
+                    Ok(blocks) => blocks,
+                    Err(e) => {
+                        log::warn!(target: &relay, "unable to fetch block range: {}", e);
+                        break;
+                    }
+                };
+                stats.lock().expect("unable to acquire lock").fetched();
+                let blocks: Vec<(Point, Vec<u8>)> = blocks
+                    .into_iter()
+                    .map(|body| {
+                        let point = BodySlurp::body_point(&body).expect("unrecognized block");
+                        log::info!(target: &relay, "downloaded block {:?} ({} bytes)", point, body.len());
+                        (point, body)
+                    })
+                    .collect();
+                // The whole range is saved, and indexed, together, before we move past any of it
+                bodies.write_batch(&blocks).expect("could not save blocks");
+                for (point, body) in blocks {
+                    if !BodySlurp::advance(cursor.clone(), frontier.clone(), &events, &stopping, &relay, &directory, point, body) {
+                        return;
+                    }
+                    stats.lock().expect("unable to acquire lock").received_block();
                 }
             }
         }));
     }
 
+    /// Stop waiting on the event sinks, so that we wind down promptly once the connection is closed
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.join_handle.as_ref().is_some_and(|jh| jh.is_finished())
+    }
+
     pub fn join(&mut self) -> thread::Result<()> {
         match self.join_handle.take() {
             Some(jh) => jh.join(),
@@ -0,0 +1,396 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use pallas::network::miniprotocols::Point;
+use serde::{Deserialize, Serialize};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use crate::{
+    manifest::CompressionSummary,
+    storage::{self, Archive, ArtifactLocation, Layout, Storage},
+    utils::era_tag,
+};
+
+// [Compression]: Bodies can be stored as zstd frames rather than raw CBOR. Each frame is compressed with
+// a dictionary trained on blocks from the same era, and zstd records the id of that dictionary in the
+// frame, so reading one back only needs {directory}/dictionaries/{id}.dict. Which dictionary to compress
+// new blocks with is recorded in {directory}/dictionaries/eras.json, and its presence turns compression on.
+// CBOR bodies always start with an array header, so they can't be mistaken for a zstd frame.
+
+pub const DICTIONARIES_NAME: &str = "dictionaries";
+const ERAS_NAME: &str = "eras.json";
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// The largest dictionary we'll train, which is what zstd itself suggests
+const DICTIONARY_SIZE: usize = 112_640;
+/// Fewer samples than this, and zstd won't produce a useful dictionary
+const MIN_SAMPLES: usize = 16;
+
+/// The settings we compress new bodies with, as recorded in eras.json
+#[derive(Serialize, Deserialize)]
+struct CompressionSettings {
+    level: i32,
+    /// The id of the dictionary to use for each era; eras without one are compressed without a dictionary
+    eras: BTreeMap<String, u32>,
+}
+
+/// The name of the era a block body is from, going by its era tag
+pub fn era_name(body: &[u8]) -> &'static str {
+    match era_tag(body).unwrap_or(u16::MAX) {
+        0 | 1 => "byron",
+        2 => "shelley",
+        3 => "allegra",
+        4 => "mary",
+        5 => "alonzo",
+        6 => "babbage",
+        7 => "conway",
+        _ => "unknown",
+    }
+}
+
+/// Compresses and decompresses bodies, with the dictionaries kept alongside the archive
+pub struct Compression {
+    directory: PathBuf,
+    /// How to compress new bodies, if compression is turned on
+    settings: Option<CompressionSettings>,
+    encoders: HashMap<String, EncoderDictionary<'static>>,
+    decoders: RwLock<HashMap<u32, Arc<DecoderDictionary<'static>>>>,
+}
+
+impl Compression {
+    pub fn load(directory: &Path) -> anyhow::Result<Self> {
+        let directory = directory.join(DICTIONARIES_NAME);
+        let eras_file = directory.join(ERAS_NAME);
+        let settings: Option<CompressionSettings> = if eras_file.exists() {
+            let file = fs::File::open(&eras_file)?;
+            Some(serde_json::from_reader(file).with_context(|| format!("unable to parse {:?}", eras_file))?)
+        } else {
+            None
+        };
+        let mut compression = Self {
+            directory,
+            settings: None,
+            encoders: HashMap::new(),
+            decoders: RwLock::new(HashMap::new()),
+        };
+        if let Some(settings) = settings {
+            compression.set_settings(settings)?;
+        }
+        Ok(compression)
+    }
+
+    fn set_settings(&mut self, settings: CompressionSettings) -> anyhow::Result<()> {
+        for (era, id) in &settings.eras {
+            let dictionary = self.read_dictionary(*id)?;
+            self.encoders.insert(era.clone(), EncoderDictionary::copy(&dictionary, settings.level));
+        }
+        self.settings = Some(settings);
+        Ok(())
+    }
+
+    fn dictionary_path(&self, id: u32) -> PathBuf {
+        self.directory.join(format!("{}.dict", id))
+    }
+
+    fn read_dictionary(&self, id: u32) -> anyhow::Result<Vec<u8>> {
+        let path = self.dictionary_path(id);
+        fs::read(&path).with_context(|| format!("unable to read compression dictionary {:?}", path))
+    }
+
+    /// How new bodies are being compressed, for the archive manifest
+    pub fn summary(&self) -> Option<CompressionSummary> {
+        self.settings.as_ref().map(|settings| CompressionSummary {
+            algorithm: "zstd".to_string(),
+            level: settings.level,
+            dictionaries: settings.eras.clone(),
+        })
+    }
+
+    /// Compress a body for storage, if compression is turned on
+    pub fn compress(&self, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let Some(settings) = &self.settings else { return Ok(body.to_vec()) };
+        let mut compressor = match self.encoders.get(era_name(body)) {
+            Some(dictionary) => zstd::bulk::Compressor::with_prepared_dictionary(dictionary)?,
+            None => zstd::bulk::Compressor::new(settings.level)?,
+        };
+        Ok(compressor.compress(body)?)
+    }
+
+    /// Turn stored bytes back into a body, whether or not they were compressed
+    pub fn decompress(&self, bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if !bytes.starts_with(&ZSTD_MAGIC) {
+            return Ok(bytes);
+        }
+        let mut body = vec![];
+        match zstd::zstd_safe::get_dict_id_from_frame(&bytes) {
+            Some(id) => {
+                let dictionary = self.decoder(id.get())?;
+                zstd::stream::Decoder::with_prepared_dictionary(&bytes[..], &dictionary)?.read_to_end(&mut body)?;
+            }
+            None => {
+                zstd::stream::Decoder::new(&bytes[..])?.read_to_end(&mut body)?;
+            }
+        };
+        Ok(body)
+    }
+
+    fn decoder(&self, id: u32) -> anyhow::Result<Arc<DecoderDictionary<'static>>> {
+        if let Some(dictionary) = self.decoders.read().expect("unable to acquire lock").get(&id) {
+            return Ok(dictionary.clone());
+        }
+        let dictionary = Arc::new(DecoderDictionary::copy(&self.read_dictionary(id)?));
+        self.decoders
+            .write()
+            .expect("unable to acquire lock")
+            .insert(id, dictionary.clone());
+        Ok(dictionary)
+    }
+
+    /// Train a dictionary for each era from sample bodies, and start compressing with them
+    ///
+    /// Returns the new dictionaries by id, which need saving with `save` before anything they've compressed is
+    fn train(&mut self, samples: &BTreeMap<&'static str, Vec<Vec<u8>>>, level: i32) -> anyhow::Result<Vec<(u32, Vec<u8>)>> {
+        let mut eras = BTreeMap::new();
+        let mut dictionaries = vec![];
+        for (era, bodies) in samples {
+            if bodies.len() < MIN_SAMPLES {
+                log::info!("only {} sample blocks from {}, so it won't have a dictionary", bodies.len(), era);
+                continue;
+            }
+            let dictionary = match zstd::dict::from_samples(bodies, DICTIONARY_SIZE) {
+                Ok(dictionary) => dictionary,
+                Err(e) => {
+                    log::warn!("unable to train a dictionary for {}: {}", era, e);
+                    continue;
+                }
+            };
+            let Some(id) = zstd::zstd_safe::get_dict_id_from_dict(&dictionary) else {
+                bail!("trained a dictionary for {} without an id", era);
+            };
+            log::info!("trained a {} byte dictionary for {} from {} blocks, with id {}", dictionary.len(), era, bodies.len(), id);
+            eras.insert(era.to_string(), id.get());
+            self.encoders.insert(era.to_string(), EncoderDictionary::copy(&dictionary, level));
+            self.decoders
+                .write()
+                .expect("unable to acquire lock")
+                .insert(id.get(), Arc::new(DecoderDictionary::copy(&dictionary)));
+            dictionaries.push((id.get(), dictionary));
+        }
+        self.settings = Some(CompressionSettings { level, eras });
+        Ok(dictionaries)
+    }
+
+    /// Save newly trained dictionaries, and make the current settings the ones new bodies are compressed with
+    fn save(&self, dictionaries: &[(u32, Vec<u8>)]) -> anyhow::Result<()> {
+        let Some(settings) = &self.settings else { return Ok(()) };
+        fs::create_dir_all(&self.directory)?;
+        // Dictionaries are never removed, since bodies compressed with an older one may still be around
+        for (id, dictionary) in dictionaries {
+            fs::write(self.dictionary_path(*id), dictionary)?;
+        }
+        fs::write(self.directory.join(ERAS_NAME), serde_json::to_vec_pretty(settings)?)?;
+        Ok(())
+    }
+}
+
+/// A store of bodies that compresses them on the way in, and decompresses them on the way out
+pub struct CompressedStorage {
+    inner: Arc<dyn Storage>,
+    compression: Arc<Compression>,
+}
+
+impl CompressedStorage {
+    pub fn new(inner: Arc<dyn Storage>, compression: Arc<Compression>) -> Self {
+        Self { inner, compression }
+    }
+}
+
+impl Storage for CompressedStorage {
+    fn write(&self, point: &Point, bytes: &[u8]) -> anyhow::Result<()> {
+        self.inner.write(point, &self.compression.compress(bytes)?)
+    }
+
+    fn write_batch(&self, artifacts: &[(Point, Vec<u8>)]) -> anyhow::Result<()> {
+        let compressed = artifacts
+            .iter()
+            .map(|(point, bytes)| Ok((point.clone(), self.compression.compress(bytes)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.inner.write_batch(&compressed)
+    }
+
+    fn read(&self, point: &Point) -> anyhow::Result<Option<Vec<u8>>> {
+        self.inner.read(point)?.map(|bytes| self.compression.decompress(bytes)).transpose()
+    }
+
+    fn contains(&self, point: &Point) -> bool {
+        self.inner.contains(point)
+    }
+
+    fn location(&self, point: &Point) -> Option<ArtifactLocation> {
+        self.inner.location(point)
+    }
+
+    fn points_before(&self, max_slot: u64) -> Box<dyn Iterator<Item = Point> + '_> {
+        self.inner.points_before(max_slot)
+    }
+
+    fn points(&self) -> Box<dyn Iterator<Item = Point> + '_> {
+        self.inner.points()
+    }
+}
+
+/// How well one era's bodies compressed
+#[derive(Default)]
+struct EraReport {
+    blocks: u64,
+    raw_bytes: u64,
+    compressed_bytes: u64,
+    compressing: Duration,
+    decompressing: Duration,
+}
+
+fn megabytes_per_second(bytes: u64, duration: Duration) -> f64 {
+    bytes as f64 / 1_000_000.0 / duration.as_secs_f64().max(f64::EPSILON)
+}
+
+/// Sample bodies evenly across the whole archive, so each era gets its fair share, grouped by era
+fn sample(archive: &Archive, sample_size: usize) -> anyhow::Result<BTreeMap<&'static str, Vec<Vec<u8>>>> {
+    let total = archive.bodies.points().count();
+    let stride = (total / sample_size.max(1)).max(1);
+    let mut samples: BTreeMap<&'static str, Vec<Vec<u8>>> = BTreeMap::new();
+    for point in archive.bodies.points().step_by(stride) {
+        let Some(body) = archive.bodies.read(&point)? else { continue };
+        samples.entry(era_name(&body)).or_default().push(body);
+    }
+    log::info!("sampled {} of {} blocks", samples.values().map(Vec::len).sum::<usize>(), total);
+    Ok(samples)
+}
+
+/// Compress a body, checking it comes back out the same, and add how it went to the report for its era
+fn measure(compression: &Compression, reports: &mut BTreeMap<&'static str, EraReport>, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let report = reports.entry(era_name(body)).or_default();
+    let started = Instant::now();
+    let compressed = compression.compress(body)?;
+    report.compressing += started.elapsed();
+
+    // Make sure every block survives the trip, which doubles as a measure of how fast reads will be
+    let started = Instant::now();
+    if compression.decompress(compressed.clone())? != body {
+        bail!("a {} block didn't survive compression", era_name(body));
+    }
+    report.decompressing += started.elapsed();
+
+    report.blocks += 1;
+    report.raw_bytes += body.len() as u64;
+    report.compressed_bytes += compressed.len() as u64;
+    Ok(compressed)
+}
+
+/// A table of how well, and how quickly, each era compressed, a line at a time
+fn report_table(reports: &BTreeMap<&'static str, EraReport>) -> Vec<String> {
+    let mut lines = vec!["era        blocks      raw MB  compressed MB  ratio  compress MB/s  decompress MB/s".to_string()];
+    for (era, report) in reports {
+        lines.push(format!(
+            "{:<8} {:>8} {:>11.1} {:>14.1} {:>6.2} {:>14.1} {:>16.1}",
+            era,
+            report.blocks,
+            report.raw_bytes as f64 / 1_000_000.0,
+            report.compressed_bytes as f64 / 1_000_000.0,
+            report.raw_bytes as f64 / report.compressed_bytes.max(1) as f64,
+            megabytes_per_second(report.raw_bytes, report.compressing),
+            megabytes_per_second(report.raw_bytes, report.decompressing),
+        ));
+    }
+    lines
+}
+
+/// Train new dictionaries from the archive, and compress every body with them
+///
+/// Bodies are rewritten into a fresh copy of the archive, which replaces the old one once complete, in
+/// the same way as a migration. With `dry_run`, nothing is written, but the report is still produced.
+pub fn recompress(directory: &Path, layout: Layout, level: i32, sample_size: usize, dry_run: bool) -> anyhow::Result<()> {
+    let archive = Archive::open(directory, layout)?;
+    let mut compression = Compression::load(directory)?;
+
+    let samples = sample(&archive, sample_size)?;
+    // Start afresh, so that eras we no longer have a dictionary for don't keep using an old one
+    compression.encoders.clear();
+    let dictionaries = compression.train(&samples, level)?;
+    drop(samples);
+
+    let mut reports: BTreeMap<&'static str, EraReport> = BTreeMap::new();
+    let mut compress = |_: &Point, body: Vec<u8>| measure(&compression, &mut reports, &body);
+
+    if dry_run {
+        for point in archive.bodies.points() {
+            let Some(body) = archive.bodies.read(&point)? else { continue };
+            compress(&point, body)?;
+        }
+    } else {
+        // The dictionaries have to be in place before any body compressed with them is
+        compression.save(&dictionaries)?;
+        storage::rebuild(directory, layout, &archive, layout, &mut compress)?;
+    }
+
+    for line in report_table(&reports) {
+        log::info!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::{
+        body_slurp::BodySlurp,
+        testing::{ebb_chain, TempDir},
+        utils::extract_header,
+    };
+
+    /// Measures the compression ratio and throughput of each era, as `recompress` would see them
+    ///
+    /// Set `SLURP_BENCH_ARCHIVE` to the directory of a real archive to measure its blocks, otherwise a synthetic
+    /// byron chain is used. Run it in release mode, as the numbers are meaningless otherwise:
+    /// `SLURP_BENCH_ARCHIVE=db cargo test --release compression_by_era -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn compression_by_era() {
+        let scratch = TempDir::new("compression");
+        let archive = match env::var_os("SLURP_BENCH_ARCHIVE") {
+            Some(directory) => {
+                let directory = PathBuf::from(directory);
+                let layout = Layout::detect(&directory).expect("no archive to measure");
+                Archive::open(&directory, layout).unwrap()
+            }
+            None => {
+                let archive = Archive::open_raw(&scratch.path().join("archive"), Layout::Files).unwrap();
+                for block in ebb_chain(2_000) {
+                    let point = BodySlurp::body_point(&block).unwrap();
+                    archive.headers.write(&point, extract_header(&block).unwrap()).unwrap();
+                    archive.bodies.write(&point, &block).unwrap();
+                }
+                archive
+            }
+        };
+
+        let mut compression = Compression::load(scratch.path()).unwrap();
+        compression.train(&sample(&archive, 10_000).unwrap(), 9).unwrap();
+        let mut reports = BTreeMap::new();
+        for point in archive.bodies.points() {
+            let body = archive.bodies.read(&point).unwrap().unwrap();
+            measure(&compression, &mut reports, &body).unwrap();
+        }
+        assert!(!reports.is_empty(), "there were no blocks to measure");
+        for line in report_table(&reports) {
+            println!("{}", line);
+        }
+    }
+}
@@ -1,47 +1,433 @@
-use std::collections::VecDeque;
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use pallas::network::miniprotocols::Point;
-use minicbor::{Encode, Decode, bytes::ByteArray};
+use anyhow::{bail, Context};
+
+use minicbor::{bytes::ByteArray, data::Type, decode, Decode, Decoder, Encode, Encoder};
+use pallas::network::miniprotocols::{chainsync::Tip, Point};
+
+use crate::storage::Storage;
+
+/// The version of the cursor file format written by this build
+///
+/// Version 0 is the original, unversioned format, which stored Origin as slot 0 with a zeroed hash.
+/// Version 1 added an explicit encoding for Origin, and version 2 wraps the points in an envelope
+/// describing the network, relay and tip the cursor was recorded against.
+pub const CURSOR_VERSION: u8 = 2;
 
 #[derive(Clone, Encode, Decode)]
-pub struct SerializablePoint {
+pub enum SerializablePoint {
     #[n(0)]
-    pub slot: u64,
+    Origin,
     #[n(1)]
-    pub hash: ByteArray<32>,
+    Specific(#[n(0)] u64, #[n(1)] ByteArray<32>),
+}
+
+impl SerializablePoint {
+    pub fn slot(&self) -> Option<u64> {
+        match self {
+            SerializablePoint::Origin => None,
+            SerializablePoint::Specific(slot, _) => Some(*slot),
+        }
+    }
 }
 
 impl From<Point> for SerializablePoint {
     fn from(value: Point) -> Self {
         match value {
-            Point::Origin => SerializablePoint { slot: 0, hash: ByteArray::from([0; 32]) },
-            Point::Specific(slot, hash) => SerializablePoint { slot, hash: ByteArray::from(TryInto::<[u8; 32]>::try_into(hash).expect("invalid hash")) },
+            Point::Origin => SerializablePoint::Origin,
+            Point::Specific(slot, hash) => SerializablePoint::Specific(
+                slot,
+                ByteArray::from(TryInto::<[u8; 32]>::try_into(hash).expect("invalid hash")),
+            ),
         }
     }
 }
 
-impl Into<Point> for SerializablePoint {
-    fn into(self) -> Point {
-        if self.slot == 0 {
-            Point::Origin
+impl From<SerializablePoint> for Point {
+    fn from(value: SerializablePoint) -> Self {
+        match value {
+            SerializablePoint::Origin => Point::Origin,
+            SerializablePoint::Specific(slot, hash) => Point::Specific(slot, hash.to_vec()),
+        }
+    }
+}
+
+/// A point as it was serialized in version 0 cursor files
+#[derive(Clone, Encode, Decode)]
+struct LegacyPoint {
+    #[n(0)]
+    slot: u64,
+    #[n(1)]
+    hash: ByteArray<32>,
+}
+
+impl From<LegacyPoint> for SerializablePoint {
+    fn from(value: LegacyPoint) -> Self {
+        // Version 0 wrote Origin as slot 0 with a zeroed hash; anything else at slot 0
+        // (such as the byron genesis EBB) is a real block
+        if value.slot == 0 && value.hash.iter().all(|b| *b == 0) {
+            SerializablePoint::Origin
         } else {
-            Point::Specific(self.slot, self.hash.to_vec())
+            SerializablePoint::Specific(value.slot, value.hash)
         }
     }
 }
 
+#[derive(Encode, Decode)]
+struct LegacyCursor {
+    #[n(0)]
+    points: VecDeque<LegacyPoint>,
+}
+
+/// The body of a version 1 cursor file
+#[derive(Encode, Decode)]
+struct PointsOnlyCursor {
+    #[n(0)]
+    points: VecDeque<SerializablePoint>,
+}
+
+#[derive(Clone, Encode, Decode)]
+pub struct SerializableTip {
+    #[n(0)]
+    pub point: SerializablePoint,
+    #[n(1)]
+    pub block_number: u64,
+}
+
+impl From<Tip> for SerializableTip {
+    fn from(value: Tip) -> Self {
+        SerializableTip {
+            point: value.0.into(),
+            block_number: value.1,
+        }
+    }
+}
+
+impl From<SerializableTip> for Tip {
+    fn from(value: SerializableTip) -> Self {
+        Tip(value.point.into(), value.block_number)
+    }
+}
+
 #[derive(Encode, Decode)]
 pub struct Cursor {
     #[n(0)]
-    pub points: VecDeque<SerializablePoint>
+    pub points: VecDeque<SerializablePoint>,
+    /// The network magic of the chain these points belong to
+    #[n(1)]
+    pub magic: u64,
+    /// The relay this cursor tracks
+    #[n(2)]
+    pub relay: String,
+    /// The last tip the relay reported to us
+    #[n(3)]
+    pub tip: Option<SerializableTip>,
+    /// Seconds since the unix epoch at which the cursor was last saved
+    #[n(4)]
+    pub updated_at: u64,
+}
+
+/// A cursor file as decoded from disk, before it has been checked against the current configuration
+enum DecodedCursor {
+    Current(Cursor),
+    Migrated(VecDeque<SerializablePoint>),
 }
 
 const CURSOR_BACKLOG: usize = 20;
+
+/// The name recorded in the frontier cursor, in place of a relay
+pub const FRONTIER_NAME: &str = "frontier";
+/// How many of the most recent points the frontier keeps before it starts thinning them out
+const FRONTIER_RECENT: usize = 10;
+/// What's added to the name of a cursor file while it's being written, before it's moved into place
+const TEMPORARY_SUFFIX: &str = ".tmp";
+
 impl Cursor {
+    pub fn new(relay: String, magic: u64, points: VecDeque<SerializablePoint>) -> Self {
+        Cursor {
+            points,
+            magic,
+            relay,
+            tip: None,
+            updated_at: 0,
+        }
+    }
+
     pub fn add_point(&mut self, value: Point) {
         self.points.push_front(value.into());
-        if self.points.len() > CURSOR_BACKLOG {
-            self.points.pop_back();
+        self.points.truncate(CURSOR_BACKLOG);
+    }
+
+    /// The points to offer a relay when looking for an intersection
+    ///
+    /// The cursor only remembers the most recent points, so after a deep rollback, or if the relay is on
+    /// another fork, we may need to reach further back into the bodies we've stored to find an intersection.
+    /// Headers are no good for this: they're saved before their bodies are fetched, so picking up from the
+    /// newest header could skip the bodies we hadn't got to yet.
+    pub fn intersection_candidates(&self, bodies: &dyn Storage) -> Vec<Point> {
+        let mut known_points: Vec<Point> = self.points.iter().map(|x| x.clone().into()).collect();
+        for point in crate::utils::exponential_points(bodies) {
+            if !known_points.contains(&point) {
+                known_points.push(point);
+            }
+        }
+        // Relays take the first point they recognize, so offer the newest first, and origin only as a last resort
+        known_points.sort_by_key(|p| match p {
+            Point::Origin => (true, Reverse(0)),
+            Point::Specific(slot, _) => (false, Reverse(*slot)),
+        });
+        known_points
+    }
+
+    /// Advance the archive frontier to a newly persisted point
+    ///
+    /// Unlike a relay cursor, the frontier retains points at exponentially growing distances from
+    /// its head, the same way the node picks points for find_intersect, so that any relay sharing
+    /// some part of our chain can find an intersection with it.
+    pub fn add_frontier_point(&mut self, value: Point) {
+        let value: SerializablePoint = value.into();
+        let Some(head) = value.slot() else { return };
+        if let Some(Some(current)) = self.points.front().map(|p| p.slot()) {
+            if head <= current {
+                return;
+            }
+        }
+        self.points.push_front(value);
+
+        // Points are ordered from newest to oldest, so of the points that fall in the same
+        // power-of-two distance from the head, we keep the last (oldest) one we see
+        let mut kept = VecDeque::new();
+        let mut last_bucket = None;
+        for (idx, point) in self.points.drain(..).enumerate() {
+            if idx < FRONTIER_RECENT {
+                kept.push_back(point);
+                continue;
+            }
+            let bucket = match point.slot() {
+                Some(slot) => (head - slot).max(1).ilog2(),
+                None => u32::MAX,
+            };
+            if last_bucket == Some(bucket) {
+                kept.pop_back();
+            }
+            last_bucket = Some(bucket);
+            kept.push_back(point);
         }
+        self.points = kept;
     }
-}
\ No newline at end of file
+
+    /// Decode a cursor file, migrating from older versions of the format as needed
+    fn decode(bytes: &[u8]) -> Result<DecodedCursor, decode::Error> {
+        // Version 0 files are a bare array of points, while versioned files lead with the version number
+        let mut probe = Decoder::new(bytes);
+        probe.array()?;
+        match probe.datatype()? {
+            Type::Array | Type::ArrayIndef => {
+                let legacy: LegacyCursor = minicbor::decode(bytes)?;
+                Ok(DecodedCursor::Migrated(
+                    legacy.points.into_iter().map(|p| p.into()).collect(),
+                ))
+            }
+            _ => {
+                let mut d = Decoder::new(bytes);
+                d.array()?;
+                match d.u8()? {
+                    1 => Ok(DecodedCursor::Migrated(d.decode::<PointsOnlyCursor>()?.points)),
+                    CURSOR_VERSION => Ok(DecodedCursor::Current(d.decode()?)),
+                    v => Err(decode::Error::message(format!("unsupported cursor version {}", v))),
+                }
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut e = Encoder::new(vec![]);
+        e.array(2)
+            .and_then(|e| e.u8(CURSOR_VERSION))
+            .and_then(|e| e.encode(self))
+            .expect("unable to serialize cursor");
+        e.into_writer()
+    }
+
+    /// Load a cursor file, and make sure it was recorded against the network we're about to sync
+    ///
+    /// Cursors from older versions of the format carry no metadata, so they are assumed to belong to
+    /// the current configuration, and will be rewritten in the current format on the next save
+    pub fn load(path: &Path, relay: &str, magic: u64) -> anyhow::Result<Cursor> {
+        let bytes = fs::read(path)?;
+        let cursor = match Cursor::decode(&bytes)? {
+            DecodedCursor::Current(cursor) => cursor,
+            DecodedCursor::Migrated(points) => {
+                log::info!("migrating cursor file {:?} to version {}", path, CURSOR_VERSION);
+                Cursor::new(relay.to_string(), magic, points)
+            }
+        };
+
+        if cursor.magic != magic {
+            bail!(
+                "cursor file {:?} was recorded on network magic {}, but we are using network magic {}",
+                path,
+                cursor.magic,
+                magic
+            );
+        }
+        if cursor.relay != relay {
+            log::warn!(
+                "cursor file {:?} was recorded for relay {}, but is being used for {}",
+                path,
+                cursor.relay,
+                relay
+            );
+        }
+
+        Ok(cursor)
+    }
+
+    /// The cursor to slurp from a source with, from its own cursor file if it has one, and otherwise starting
+    /// from wherever the archive as a whole has reached, then the default point, then origin
+    pub fn resume(
+        directory: &Path,
+        relay: &str,
+        magic: u64,
+        frontier_mutex: &Mutex<Cursor>,
+        default_point: Option<Point>,
+    ) -> anyhow::Result<Cursor> {
+        fs::create_dir_all(directory.join("cursors"))?;
+        let cursor_file = directory.join("cursors").join(relay);
+        if cursor_file.exists() {
+            log::info!(target: relay, "reading cursor file");
+            return Cursor::load(&cursor_file, relay, magic);
+        }
+
+        let mut points = frontier_mutex.lock().expect("unable to acquire lock").points.clone();
+        if !points.is_empty() {
+            log::info!(target: relay, "syncing from archive frontier");
+        }
+        if let Some(default_point) = default_point {
+            log::info!(target: relay, "syncing from default point {:?}", &default_point);
+            points.push_back(default_point.into());
+        }
+        if points.is_empty() {
+            log::info!(target: relay, "syncing from origin");
+            points.push_back(Point::Origin.into());
+        }
+        Ok(Cursor::new(relay.to_string(), magic, points))
+    }
+
+    /// Save the cursor, replacing the file in one step, so a crash part way through leaves the previous cursor
+    /// rather than a truncated one
+    pub fn save(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.updated_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(TEMPORARY_SUFFIX);
+        fs::write(&temporary, self.to_bytes())?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+/// Load the archive frontier, the cursor tracking how far the archive as a whole has been synced
+///
+/// If there is no frontier yet, it is seeded from the points in each relay's cursor
+pub fn load_frontier(directory: &Path, magic: u64) -> anyhow::Result<Cursor> {
+    let frontier_file = directory.join(FRONTIER_NAME);
+    if frontier_file.exists() {
+        return Cursor::load(&frontier_file, FRONTIER_NAME, magic);
+    }
+
+    let mut frontier = Cursor::new(FRONTIER_NAME.to_string(), magic, VecDeque::new());
+    let mut points = vec![];
+    for (relay, path) in relay_cursors(directory)? {
+        match Cursor::load(&path, &relay, magic) {
+            Ok(cursor) => points.extend(cursor.points),
+            Err(e) => log::warn!("skipping cursor {:?} while seeding the frontier: {}", path, e),
+        }
+    }
+    points.sort_by_key(|p| p.slot());
+    for point in points {
+        frontier.add_frontier_point(point.into());
+    }
+    Ok(frontier)
+}
+
+/// The network magic the archive's cursors were recorded on, going by the frontier, or else any relay's cursor
+///
+/// Cursors from older versions of the format don't record one.
+pub fn recorded_magic(directory: &Path) -> Option<u64> {
+    let mut cursors = vec![directory.join(FRONTIER_NAME)];
+    cursors.extend(relay_cursors(directory).ok()?.into_iter().map(|(_, path)| path));
+    cursors.iter().find_map(|path| match Cursor::decode(&fs::read(path).ok()?).ok()? {
+        DecodedCursor::Current(cursor) => Some(cursor.magic),
+        DecodedCursor::Migrated(_) => None,
+    })
+}
+
+/// The cursor file of each relay we've slurped from, by relay, leaving out any left half written by a crash
+pub fn relay_cursors(directory: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let cursor_directory = directory.join("cursors");
+    if !cursor_directory.exists() {
+        return Ok(vec![]);
+    }
+    let mut cursors = vec![];
+    for entry in fs::read_dir(cursor_directory)? {
+        let entry = entry?;
+        let relay = entry.file_name().to_string_lossy().to_string();
+        if !relay.ends_with(TEMPORARY_SUFFIX) {
+            cursors.push((relay, entry.path()));
+        }
+    }
+    cursors.sort();
+    Ok(cursors)
+}
+
+/// Make sure every relay's cursor can be read, and was recorded on the network we're about to sync
+pub fn check_cursors(directory: &Path, magic: u64) -> anyhow::Result<()> {
+    for (relay, path) in relay_cursors(directory)? {
+        Cursor::load(&path, &relay, magic).with_context(|| format!("unable to load the cursor for {}", relay))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::MAINNET_MAGIC;
+
+    use super::*;
+    use crate::{body_slurp::BodySlurp, testing::{ebb_chain, TempDir}};
+
+    #[test]
+    fn legacy_origin_and_genesis_ebb_survive_migration() {
+        let directory = TempDir::new("cursor-migration");
+        let path = directory.path().join("relay");
+
+        // The byron genesis EBB sits at slot 0 too, but with a real hash
+        let Some(Point::Specific(0, ebb_hash)) = BodySlurp::body_point(&ebb_chain(1)[0]) else {
+            panic!("invalid test block")
+        };
+        let legacy = LegacyCursor {
+            points: VecDeque::from([
+                LegacyPoint { slot: 0, hash: ByteArray::from(<[u8; 32]>::try_from(ebb_hash.clone()).unwrap()) },
+                LegacyPoint { slot: 0, hash: ByteArray::from([0; 32]) },
+            ]),
+        };
+        fs::write(&path, minicbor::to_vec(&legacy).unwrap()).unwrap();
+
+        let expected = vec![Point::Specific(0, ebb_hash), Point::Origin];
+        let mut migrated = Cursor::load(&path, "relay", MAINNET_MAGIC).unwrap();
+        let points: Vec<Point> = migrated.points.iter().cloned().map(Point::from).collect();
+        assert_eq!(points, expected);
+
+        // Once rewritten in the current format, both read back as they were
+        migrated.save(&path).unwrap();
+        let reloaded = Cursor::load(&path, "relay", MAINNET_MAGIC).unwrap();
+        let points: Vec<Point> = reloaded.points.into_iter().map(Point::from).collect();
+        assert_eq!(points, expected);
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use pallas::network::miniprotocols::Point;
+use serde_json::{json, Value};
+use tungstenite::{
+    handshake::server::{Request, Response},
+    Message,
+};
+
+use crate::{args::parse_point, index::Index, storage::Archive, utils::point_json};
+
+/// How many events a subscriber can fall behind by before we give up on it
+const SUBSCRIBER_BUFFER: usize = 10_000;
+/// What we say events replayed from the archive came from, in place of a relay
+const REPLAY_NAME: &str = "archive";
+/// How long to wait before trying a sink again, at first, and at most
+const SINK_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_SINK_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How often to check whether to stop, while waiting to retry a sink
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A change to the chain, as one of the slurps saw it
+pub enum Event {
+    /// A block was saved, after those before it from the same relay
+    RollForward { relay: String, point: Point, block: Vec<u8> },
+    /// The relay switched forks, and blocks after this point from it are no longer on its chain
+    RollBackward { relay: String, point: Point },
+}
+
+impl Event {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Event::RollForward { relay, point, block } => json!({
+                "type": "roll_forward",
+                "relay": relay,
+                "point": point_json(point),
+                "block": hex::encode(block),
+            }),
+            Event::RollBackward { relay, point } => json!({
+                "type": "roll_backward",
+                "relay": relay,
+                "point": point_json(point),
+            }),
+        }
+    }
+}
+
+/// Somewhere every event has to get to before the slurp that published it carries on, such as a message broker
+pub trait Sink: Send + Sync {
+    fn publish(&self, event: &Event) -> anyhow::Result<()>;
+}
+
+/// Hands the events from every slurp to the sinks, and to whoever has subscribed to them
+///
+/// Each relay's events are published in the order its slurps processed them, but events from different relays
+/// are interleaved, so the same block can come more than once; consumers should expect that, and key on hashes.
+///
+/// Publishing blocks until every sink has taken the event, retrying for as long as it takes (or until the slurp
+/// publishing it stops), and blocks are published before the cursors move past them, so a sink sees every block at
+/// least once, even across restarts.
+/// Subscribers, on the other hand, are never waited on: one that falls more than [SUBSCRIBER_BUFFER] events
+/// behind is dropped, and can resume from the last point it handled.
+#[derive(Default)]
+pub struct Events {
+    sinks: Vec<Box<dyn Sink>>,
+    subscribers: Mutex<Vec<SyncSender<Arc<Event>>>>,
+}
+
+impl Events {
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.publish_until(event, &AtomicBool::new(false));
+    }
+
+    /// Publish an event, but stop retrying the sinks once `stopping` is set, returning whether every sink took it
+    ///
+    /// A slurp that's being torn down has no use for the event any more: its cursor stays put, so the block is
+    /// fetched and published again on the next connection.
+    pub fn publish_until(&self, event: Event, stopping: &AtomicBool) -> bool {
+        for sink in &self.sinks {
+            let mut delay = SINK_RETRY_DELAY;
+            while let Err(e) = sink.publish(&event) {
+                if stopping.load(Ordering::Relaxed) {
+                    log::warn!("unable to publish event, giving up as we're stopping: {:#}", e);
+                    return false;
+                }
+                log::warn!("unable to publish event, retrying in {:?}: {:#}", delay, e);
+                let retry_at = Instant::now() + delay;
+                while Instant::now() < retry_at && !stopping.load(Ordering::Relaxed) {
+                    thread::sleep(STOP_POLL_INTERVAL);
+                }
+                delay = (delay * 2).min(MAX_SINK_RETRY_DELAY);
+            }
+        }
+
+        let mut subscribers = self.subscribers.lock().expect("unable to acquire lock");
+        if subscribers.is_empty() {
+            return true;
+        }
+        let event = Arc::new(event);
+        subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("dropping an event subscriber that fell too far behind");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        true
+    }
+
+    pub fn subscribe(&self) -> Receiver<Arc<Event>> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        self.subscribers.lock().expect("unable to acquire lock").push(sender);
+        receiver
+    }
+}
+
+/// Sends every block after `point` on the chain the index follows, as of now, moving `point` along with it, and
+/// returns the points of the blocks sent
+///
+/// If the index has switched forks since `point`, the first thing sent is a rollback to where the forks meet.
+fn catch_up(
+    archive: &Archive,
+    index: &Index,
+    point: &mut Point,
+    send: &mut impl FnMut(&Event) -> anyhow::Result<()>,
+) -> anyhow::Result<Vec<Point>> {
+    let mut sent = vec![];
+    loop {
+        match index.successor(point)? {
+            Some(next) => {
+                let block = archive.bodies.read(&next)?.context("block isn't in the archive")?;
+                send(&Event::RollForward { relay: REPLAY_NAME.to_string(), point: next.clone(), block })?;
+                sent.push(next.clone());
+                *point = next;
+            }
+            None if index.is_on_chain(point)? => return Ok(sent),
+            None => {
+                *point = index.intersection(point)?;
+                send(&Event::RollBackward { relay: REPLAY_NAME.to_string(), point: point.clone() })?;
+            }
+        }
+    }
+}
+
+/// Subscribes to new events, first sending every block after `from` on the chain the index follows
+///
+/// The replay runs in rounds until it has caught up with the archive, which can take a while if slurping
+/// carries on meanwhile, and only then subscribes, as a subscriber that falls too far behind is dropped.
+/// A last round sends whatever was saved before the subscription started, and the live events for those
+/// blocks are skipped.
+fn replay_and_follow(
+    events: &Events,
+    archive: &Archive,
+    index: &Index,
+    from: Option<&Point>,
+    mut send: impl FnMut(&Event) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let (live, mut replayed) = match from {
+        Some(from) => {
+            if !index.is_on_chain(from)? {
+                bail!("{:?} isn't on the chain in the archive", from);
+            }
+            let mut point = from.clone();
+            while !catch_up(archive, index, &mut point, &mut send)?.is_empty() {}
+            let live = events.subscribe();
+            let replayed: HashSet<Point> = catch_up(archive, index, &mut point, &mut send)?.into_iter().collect();
+            (live, replayed)
+        }
+        None => (events.subscribe(), HashSet::new()),
+    };
+    for event in live {
+        if let Event::RollForward { point, .. } = event.as_ref() {
+            if replayed.remove(point) {
+                continue;
+            }
+        }
+        send(&event)?;
+    }
+    bail!("fell too far behind")
+}
+
+/// Write events to stdout as newline-delimited JSON, starting with a replay from `from` if given
+pub fn stream_stdout(events: Arc<Events>, archive: Archive, index: Arc<Index>, from: Option<Point>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut stdout = io::stdout();
+        let result = replay_and_follow(&events, &archive, &index, from.as_ref(), |event| {
+            writeln!(stdout, "{}", event.to_json())?;
+            Ok(stdout.flush()?)
+        });
+        if let Err(e) = result {
+            log::error!("stopped streaming events to stdout: {:#}", e);
+        }
+    })
+}
+
+/// Serves events to WebSocket clients, as a JSON text message each
+///
+/// Clients can pass `?from=slot/hash` (or any other point `--fallback-point` takes) to replay from there first.
+pub struct EventServer {
+    events: Arc<Events>,
+    archive: Archive,
+    index: Arc<Index>,
+    magic: u64,
+}
+
+impl EventServer {
+    pub fn new(events: Arc<Events>, archive: Archive, index: Arc<Index>, magic: u64) -> Self {
+        Self { events, archive, index, magic }
+    }
+
+    pub fn listen(self, address: &str) -> anyhow::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(address).with_context(|| format!("unable to listen on {}", address))?;
+        log::info!("streaming events over websockets on {}", address);
+        let server = Arc::new(self);
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warn!("unable to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let server = server.clone();
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| "unknown peer".to_string());
+                    if let Err(e) = server.serve_client(&peer, stream) {
+                        log::info!(target: &peer, "stopped streaming events: {:#}", e);
+                    }
+                });
+            }
+        }))
+    }
+
+    fn serve_client(&self, peer: &str, stream: TcpStream) -> anyhow::Result<()> {
+        let mut query = None;
+        // tungstenite's own error response type is what's large, and only built if we refuse the hand-shake
+        #[allow(clippy::result_large_err)]
+        let mut socket = tungstenite::accept_hdr(stream, |request: &Request, response: Response| {
+            query = request.uri().query().map(|q| q.to_string());
+            Ok(response)
+        })
+        .map_err(|e| anyhow!("websocket hand-shake failed: {}", e))?;
+
+        let from = query
+            .iter()
+            .flat_map(|q| q.split('&'))
+            .find_map(|pair| pair.strip_prefix("from="))
+            .map(|from| {
+                let from = from.replace("%2F", "/").replace("%2f", "/");
+                parse_point(&from).map_err(|e| anyhow!(e))?.resolve(self.archive.headers.as_ref(), self.magic)
+            })
+            .transpose();
+        let from = match from {
+            Ok(from) => from,
+            Err(e) => {
+                let _ = socket.send(Message::text(json!({ "error": format!("{:#}", e) }).to_string()));
+                return Err(e);
+            }
+        };
+        match &from {
+            Some(from) => log::info!(target: peer, "streaming events, replaying from {:?}", from),
+            None => log::info!(target: peer, "streaming new events"),
+        }
+
+        let result = replay_and_follow(&self.events, &self.archive, &self.index, from.as_ref(), |event| {
+            Ok(socket.send(Message::text(event.to_json().to_string()))?)
+        });
+        if let Err(e) = &result {
+            let _ = socket.send(Message::text(json!({ "error": format!("{:#}", e) }).to_string()));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::MAINNET_MAGIC;
+
+    use super::*;
+    use crate::{storage::Layout, testing::{babbage_block, TempDir}};
+
+    /// Save a chain of babbage blocks after `from`, or from the start, returning their points
+    fn extend(archive: &Archive, index: &Index, from: Option<&Point>, length: u64, issuer: u8) -> Vec<Point> {
+        let mut previous = from.map(|point| match point {
+            Point::Specific(_, hash) => (index.block(hash).unwrap().unwrap().height, hash.clone()),
+            Point::Origin => unreachable!(),
+        });
+        let mut points = vec![];
+        for _ in 0..length {
+            let number = previous.as_ref().map_or(0, |(height, _)| height + 1);
+            let (point, block) = babbage_block(number, number * 20 + issuer as u64, previous.as_ref().map(|(_, h)| &h[..]), issuer);
+            archive.bodies.write(&point, &block).unwrap();
+            let Point::Specific(_, hash) = &point else { unreachable!() };
+            previous = Some((number, hash.clone()));
+            points.push(point);
+        }
+        points
+    }
+
+    fn describe(event: &Event) -> String {
+        match event {
+            Event::RollForward { relay, point, .. } => format!("{} forward {:?}", relay, point),
+            Event::RollBackward { relay, point } => format!("{} backward {:?}", relay, point),
+        }
+    }
+
+    #[test]
+    fn replays_catch_up_across_forks_before_following() {
+        let directory = TempDir::new("events");
+        let index = Arc::new(Index::open(directory.path(), MAINNET_MAGIC, false).unwrap());
+        let archive = Archive::open_raw(directory.path(), Layout::Files).unwrap().indexed(index.clone());
+        let events = Arc::new(Events::default());
+        let chain = extend(&archive, &index, None, 4, 0);
+
+        let sent = Arc::new(Mutex::new(vec![]));
+        let replay = {
+            let (events, archive, index, sent, from) = (events.clone(), archive.clone(), index.clone(), sent.clone(), chain[0].clone());
+            thread::spawn(move || {
+                let mut fork = vec![];
+                let result = replay_and_follow(&events, &archive, &index, Some(&from), |event| {
+                    sent.lock().unwrap().push(describe(event));
+                    // Midway through the replay, a longer fork takes over from the first block
+                    if fork.is_empty() {
+                        fork = extend(&archive, &index, Some(&from), 5, 1);
+                    }
+                    match event {
+                        Event::RollForward { relay, .. } if relay != REPLAY_NAME => bail!("done"),
+                        _ => Ok(()),
+                    }
+                });
+                (result, fork)
+            })
+        };
+
+        // Only once the replay has caught up does it start taking new events, so keep publishing until it does
+        let started = Instant::now();
+        while !replay.is_finished() && started.elapsed() < Duration::from_secs(30) {
+            events.publish(Event::RollForward { relay: "relay".to_string(), point: Point::Origin, block: vec![] });
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (result, fork) = replay.join().unwrap();
+        assert_eq!(result.unwrap_err().to_string(), "done");
+
+        let mut expected = vec![
+            format!("archive forward {:?}", chain[1]),
+            format!("archive backward {:?}", chain[0]),
+        ];
+        expected.extend(fork.iter().map(|point| format!("archive forward {:?}", point)));
+        expected.push(format!("relay forward {:?}", Point::Origin));
+        assert_eq!(*sent.lock().unwrap(), expected);
+    }
+
+    struct FailingSink;
+
+    impl Sink for FailingSink {
+        fn publish(&self, _event: &Event) -> anyhow::Result<()> {
+            bail!("the broker is down")
+        }
+    }
+
+    #[test]
+    fn publishing_gives_up_once_stopping() {
+        let mut events = Events::default();
+        events.add_sink(Box::new(FailingSink));
+        let subscriber = events.subscribe();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let publishing = {
+            let stopping = stopping.clone();
+            thread::spawn(move || events.publish_until(Event::RollBackward { relay: "relay".to_string(), point: Point::Origin }, &stopping))
+        };
+        thread::sleep(SINK_RETRY_DELAY * 2);
+        assert!(!publishing.is_finished());
+
+        let stopped = Instant::now();
+        stopping.store(true, Ordering::Relaxed);
+        assert!(!publishing.join().unwrap());
+        assert!(stopped.elapsed() < SINK_RETRY_DELAY);
+        // Subscribers only see events the sinks took
+        assert!(subscriber.try_recv().is_err());
+    }
+}
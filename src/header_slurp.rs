@@ -1,7 +1,9 @@
 use std::{
-    fs,
-    path::{PathBuf},
-    sync::{mpsc, Arc, Mutex},
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread::{self, JoinHandle},
 };
 
@@ -20,40 +22,61 @@ use pallas::{
     },
 };
 
-use crate::cursor::Cursor;
+use anyhow::bail;
+
+use crate::{
+    body_slurp::Batch,
+    cursor::Cursor,
+    stats::PeerStats,
+    storage::{Archive, Storage},
+    webhooks::{Notification, Webhooks},
+};
+
+/// How many of the most recent headers to remember, to tell how deep a rollback is; the security parameter
+/// means the chain never rolls back further than this
+const ROLLBACK_WINDOW: usize = 2160;
 
 pub struct HeaderSlurp {
-    pub base_directory: PathBuf,
-    pub directory: PathBuf,
     pub batch_size: u8,
 
-    pub block_batches: mpsc::SyncSender<(Point, Point)>,
+    pub block_batches: Option<mpsc::SyncSender<Batch>>,
 
+    headers: Arc<dyn Storage>,
+    /// Where we look for a point to pick up from, since only points we have the body of are safe to resume after
+    bodies: Arc<dyn Storage>,
     cursor_mutex: Arc<Mutex<Cursor>>,
+    stats_mutex: Arc<Mutex<PeerStats>>,
+    webhooks: Arc<Webhooks>,
     relay: String,
+    stopping: Arc<AtomicBool>,
     join_handle: Option<JoinHandle<()>>,
 }
 
 impl HeaderSlurp {
     pub fn new(
         relay: String,
-        directory: PathBuf,
+        archive: Archive,
         batch_size: u8,
         cursor_mutex: Arc<Mutex<Cursor>>,
-        block_batches: mpsc::SyncSender<(Point, Point)>,
+        stats_mutex: Arc<Mutex<PeerStats>>,
+        block_batches: mpsc::SyncSender<Batch>,
+        webhooks: Arc<Webhooks>,
     ) -> Self {
         Self {
-            base_directory: directory.clone(),
-            directory: directory.join("headers"),
+            headers: archive.headers,
+            bodies: archive.bodies,
             relay,
             batch_size,
-            block_batches,
+            block_batches: Some(block_batches),
             cursor_mutex,
+            stats_mutex,
+            webhooks,
+            stopping: Arc::new(AtomicBool::new(false)),
             join_handle: None,
         }
     }
 
-    fn ebb_point(cbor: &Vec<u8>) -> Option<Point> {
+    fn ebb_point(cbor: &[u8]) -> Option<Point> {
         let header = minicbor::decode::<byron::EbbHead>(cbor).ok()?;
         Some(Point::Specific(
             header.consensus_data.epoch_id * 21600,
@@ -61,7 +84,7 @@ impl HeaderSlurp {
         ))
     }
 
-    fn byron_point(cbor: &Vec<u8>) -> Option<Point> {
+    fn byron_point(cbor: &[u8]) -> Option<Point> {
         let header = minicbor::decode::<byron::BlockHead>(cbor).ok()?;
         Some(Point::Specific(
             header.consensus_data.0.epoch * 21600 + header.consensus_data.0.slot,
@@ -69,7 +92,7 @@ impl HeaderSlurp {
         ))
     }
 
-    fn shelley_or_alonzo_point(cbor: &Vec<u8>) -> Option<Point> {
+    fn shelley_or_alonzo_point(cbor: &[u8]) -> Option<Point> {
         let header = minicbor::decode::<alonzo::Header>(cbor).ok()?;
         Some(Point::Specific(
             header.header_body.slot,
@@ -77,7 +100,7 @@ impl HeaderSlurp {
         ))
     }
 
-    fn babbage_point(cbor: &Vec<u8>) -> Option<Point> {
+    fn babbage_point(cbor: &[u8]) -> Option<Point> {
         let header = minicbor::decode::<babbage::Header>(cbor).ok()?;
         Some(Point::Specific(
             header.header_body.slot,
@@ -85,58 +108,97 @@ impl HeaderSlurp {
         ))
     }
 
-    fn handle_header(relay: &String, directory: &PathBuf, h: HeaderContent) -> Point {
-        // We skip byron blocks for now, because to know their slot, we need to know the slot of the *next* block, which is annoying
-        let point = HeaderSlurp::ebb_point(&h.cbor)
-            .or_else(|| HeaderSlurp::byron_point(&h.cbor))
-            .or_else(|| HeaderSlurp::shelley_or_alonzo_point(&h.cbor))
-            .or_else(|| HeaderSlurp::babbage_point(&h.cbor))
-            .expect("unrecognized block header");
-
-        log::info!(target: &relay[..11], "rolling forward, {:?}", point);
-
-        let path = crate::utils::artifact_path(directory.clone(), point.clone());
-        fs::create_dir_all(path.parent().unwrap())
-            .expect(format!("unable to creact directory {:?}", path).as_str());
-        fs::write(path, h.cbor).expect("could not save header");
-        return point;
+    /// Work out the point a block header belongs at, whichever era it is from
+    pub fn header_point(cbor: &[u8]) -> Option<Point> {
+        HeaderSlurp::ebb_point(cbor)
+            .or_else(|| HeaderSlurp::byron_point(cbor))
+            .or_else(|| HeaderSlurp::shelley_or_alonzo_point(cbor))
+            .or_else(|| HeaderSlurp::babbage_point(cbor))
     }
 
-    pub fn slurp(&mut self, channel: StdChannel) -> anyhow::Result<()> {
-        fs::create_dir_all(&self.directory).expect("couldn't create directory");
+    fn handle_header(relay: &str, headers: &dyn Storage, h: HeaderContent) -> Point {
+        let point = HeaderSlurp::header_point(&h.cbor).expect("unrecognized block header");
+
+        log::info!(target: &relay, "rolling forward, {:?}", point);
 
+        headers.write(&point, &h.cbor).expect("could not save header");
+        point
+    }
+
+    pub fn slurp(&mut self, channel: StdChannel) -> anyhow::Result<()> {
         // Read the latest cursor
         let gaurd = self.cursor_mutex.lock().unwrap();
         
-        let known_points = gaurd.points.iter().map(|x| x.clone().into()).collect();
+        let known_points = gaurd.intersection_candidates(self.bodies.as_ref());
 
         drop(gaurd);
 
         let mut client = chainsync::N2NClient::new(channel);
 
-        let (point, _) = client.find_intersect(known_points).unwrap();
+        let (point, tip) = client.find_intersect(known_points)?;
+        let Some(point) = point else {
+            bail!("no intersection found with relay, whose tip is {:?}", tip.0);
+        };
 
-        let directory = self.directory.clone();
+        let headers = self.headers.clone();
         let relay = self.relay.clone();
         let mut batch_size = self.batch_size;
-        let block_batches = self.block_batches.clone();
+        // The thread takes the only sender, so that the body slurp winds down once it does
+        let block_batches = self.block_batches.take().expect("header slurp already started");
+        let cursor_mutex = self.cursor_mutex.clone();
+        let stopping = self.stopping.clone();
+        let stats_mutex = self.stats_mutex.clone();
+        let webhooks = self.webhooks.clone();
 
-        log::info!(target: &relay[..11], "intersected point is {:?}", point);
+        log::info!(target: &relay, "intersected point is {:?}", point);
 
         self.join_handle = Some(thread::spawn(move || {
             let mut start: Option<Point> = None;
             let mut prev: Option<Point> = None;
             let mut current_batch = 0;
+            let mut recent: VecDeque<Point> = VecDeque::new();
+            let mut last_tip: Option<chainsync::Tip> = None;
+            // Whether we've caught up with the relay, since we last fell behind it
+            let mut at_tip = false;
             loop {
+                if stopping.load(Ordering::Relaxed) {
+                    // Hand off whatever is left of the current batch, so the body slurp can finish it before stopping
+                    if let (Some(s), Some(p)) = (&start, &prev) {
+                        let _ = block_batches.send(Batch::Range(s.clone(), p.clone()));
+                    }
+                    log::info!(target: &relay, "stopped following the chain");
+                    break;
+                }
+
+                stats_mutex.lock().expect("unable to acquire lock").requested_chainsync();
                 let next = if client.has_agency() {
-                  client.request_next().unwrap()
+                  client.request_next()
                 } else {
-                  client.recv_while_can_await().unwrap()
+                  client.recv_while_can_await()
                 };
+                let next = match next {
+                    Ok(next) => next,
+                    Err(e) => {
+                        log::warn!(target: &relay, "chainsync failed: {}", e);
+                        break;
+                    }
+                };
+                stats_mutex.lock().expect("unable to acquire lock").received_chainsync();
 
                 match next {
-                    chainsync::NextResponse::RollForward(h, _) => {
-                        let point = HeaderSlurp::handle_header(&relay, &directory, h);
+                    chainsync::NextResponse::RollForward(h, tip) => {
+                        last_tip = Some(tip.clone());
+                        cursor_mutex.lock().expect("unable to acquire lock").tip = Some(tip.into());
+                        let point = HeaderSlurp::handle_header(&relay, headers.as_ref(), h);
+                        if at_tip && last_tip.as_ref().is_some_and(|tip| tip.0 != point) {
+                            log::info!(target: &relay, "fell behind the tip of chain");
+                            at_tip = false;
+                            stats_mutex.lock().expect("unable to acquire lock").fell_behind();
+                        }
+                        recent.push_back(point.clone());
+                        if recent.len() > ROLLBACK_WINDOW {
+                            recent.pop_front();
+                        }
                         
                         if start.is_none() {
                             start = Some(point.clone());
@@ -148,33 +210,57 @@ impl HeaderSlurp {
                         let s = start.clone().unwrap_or(point.clone());
                         // (start, point) 
                         if current_batch >= batch_size.into() {
-                            block_batches
-                                .send((s, point.clone()))
-                                .expect("unable to send block batch");
+                            if block_batches.send(Batch::Range(s, point.clone())).is_err() {
+                                log::warn!(target: &relay, "body slurp has stopped");
+                                break;
+                            }
                             start = None;
                             current_batch = 0;
                         }
                         prev = Some(point.clone());
                     }
-                    chainsync::NextResponse::RollBackward(rollback_to, _) => {
-                        log::info!(target: &relay[..11], "rollback to {:?}", rollback_to);
+                    chainsync::NextResponse::RollBackward(rollback_to, tip) => {
+                        last_tip = Some(tip.clone());
+                        cursor_mutex.lock().expect("unable to acquire lock").tip = Some(tip.into());
+                        log::info!(target: &relay, "rollback to {:?}", rollback_to);
+                        stats_mutex.lock().expect("unable to acquire lock").rollbacks += 1;
+                        // If we don't remember the point, it's at least as deep as everything we do remember
+                        let kept = recent.iter().rposition(|p| *p == rollback_to).map_or(0, |i| i + 1);
+                        let depth = (recent.len() - kept) as u64;
+                        recent.truncate(kept);
+                        if depth > webhooks.rollback_depth {
+                            webhooks.notify(Notification::Rollback { depth }, &relay, Some(&rollback_to), last_tip.as_ref());
+                        }
                         // Make sure we download these block ranges before rolling back
                         // If we have a start point and a previous point, make sure to download the blocks in that range before we roll back
-                        match (&start, &prev) {
-                            (Some(s), Some(p)) => {
-                                block_batches
-                                    .send((s.clone(), p.clone()))
-                                    .expect("unable to send block batch before rollback");
-                            },
-                            _ => {}
+                        if let (Some(s), Some(p)) = (&start, &prev) {
+                            if block_batches.send(Batch::Range(s.clone(), p.clone())).is_err() {
+                                log::warn!(target: &relay, "body slurp has stopped");
+                                break;
+                            }
                         }
                         // And then set start to none, since we've already downloaded rollback_to (in theory)
                         start = None;
+                        if block_batches.send(Batch::RollBackward(rollback_to)).is_err() {
+                            log::warn!(target: &relay, "body slurp has stopped");
+                            break;
+                        }
                     }
                     chainsync::NextResponse::Await => {
-                        if batch_size > 1 {
-                            log::info!(target: &relay[..11], "tip of chain reached");
+                        if !at_tip {
+                            log::info!(target: &relay, "tip of chain reached");
+                            at_tip = true;
                             batch_size = 1;
+                            stats_mutex.lock().expect("unable to acquire lock").reached_tip();
+                            webhooks.notify(Notification::Tip, &relay, prev.as_ref(), last_tip.as_ref());
+                        }
+                        // Don't leave the last few blocks before the tip waiting on a batch that won't fill up
+                        if let (Some(s), Some(p)) = (start.take(), &prev) {
+                            if block_batches.send(Batch::Range(s, p.clone())).is_err() {
+                                log::warn!(target: &relay, "body slurp has stopped");
+                                break;
+                            }
+                            current_batch = 0;
                         }
                     }
                 };
@@ -183,6 +269,15 @@ impl HeaderSlurp {
         Ok(())
     }
 
+    /// Stop following the chain once the current message has been handled
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.join_handle.as_ref().is_some_and(|jh| jh.is_finished())
+    }
+
     pub fn join(&mut self) -> thread::Result<()> {
         match self.join_handle.take() {
             Some(jh) => jh.join(),
@@ -0,0 +1,234 @@
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::anyhow;
+use pallas::{ledger::traverse::MultiEraBlock, network::miniprotocols::Point};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    cursor::{relay_cursors, Cursor, FRONTIER_NAME},
+    index::Index,
+    stats::{PeerStats, PeerStatsMap},
+    storage::{Archive, Storage},
+    utils::point_json,
+};
+
+/// How many requests we serve at once
+const HTTP_THREADS: usize = 4;
+
+/// A metric we report for each peer: its name, type and description, and how to read it from the peer's stats
+type PeerMetric = (&'static str, &'static str, &'static str, fn(&PeerStats) -> f64);
+
+const PEER_METRICS: &[PeerMetric] = &[
+    ("connected", "gauge", "Whether we're connected to the peer", |s| s.is_connected() as u8 as f64),
+    ("at_tip", "gauge", "Whether we're caught up with the peer's tip on the current connection", |s| s.at_tip() as u8 as f64),
+    ("handshakes_total", "counter", "Handshakes completed with the peer", |s| s.handshakes as f64),
+    ("failed_handshakes_total", "counter", "Failed attempts to connect or handshake with the peer", |s| s.failed_handshakes as f64),
+    ("blocks_total", "counter", "Block bodies downloaded from the peer", |s| s.blocks as f64),
+    ("rollbacks_total", "counter", "Rollbacks from the peer", |s| s.rollbacks as f64),
+    ("stalls_total", "counter", "Connections to the peer given up on for going quiet", |s| s.stalls as f64),
+    ("latency_seconds", "gauge", "How long the latest handshake with the peer took", |s| s.latency.unwrap_or_default().as_secs_f64()),
+    ("keepalive_rtt_seconds", "gauge", "How long the peer took to answer the latest keep-alive", |s| {
+        s.keepalive_rtt.unwrap_or_default().as_secs_f64()
+    }),
+    ("seconds_since_chainsync", "gauge", "How long since the peer last sent a chainsync message", |s| {
+        s.since_last_chainsync().unwrap_or_default().as_secs_f64()
+    }),
+];
+
+/// A response, before it's sent
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn cbor(body: Vec<u8>) -> Reply {
+        Reply { status: 200, content_type: "application/cbor", body }
+    }
+
+    fn json(status: u16, value: Value) -> Reply {
+        Reply { status, content_type: "application/json", body: value.to_string().into_bytes() }
+    }
+
+    fn error(status: u16, message: &str) -> Reply {
+        Reply::json(status, json!({ "error": message }))
+    }
+}
+
+/// Serves blocks, headers and cursors out of the archive over HTTP, alongside syncing into it
+///
+/// Blocks and headers are returned as the raw CBOR we saved, and everything else as JSON:
+///  - `GET /blocks/{hash}` and `GET /headers/{hash}`, found through the index
+///  - `GET /blocks/slot/{slot}`, or a list of the blocks to choose from if there's more than one there
+///  - `GET /tip`, the tip of the chain the index follows
+///  - `GET /cursors`, the archive frontier and the cursor for each relay
+///  - `GET /metrics`, what we know about each peer, in the Prometheus text format
+pub struct HttpServer {
+    directory: PathBuf,
+    archive: Archive,
+    index: Arc<Index>,
+    magic: u64,
+    peers: PeerStatsMap,
+}
+
+impl HttpServer {
+    pub fn new(directory: PathBuf, archive: Archive, index: Arc<Index>, magic: u64, peers: PeerStatsMap) -> Self {
+        Self { directory, archive, index, magic, peers }
+    }
+
+    pub fn serve(self, address: &str) -> anyhow::Result<Vec<JoinHandle<()>>> {
+        let server = Arc::new(Server::http(address).map_err(|e| anyhow!("unable to listen on {}: {}", address, e))?);
+        log::info!("serving the archive over http on {}", address);
+        let api = Arc::new(self);
+        Ok((0..HTTP_THREADS)
+            .map(|_| {
+                let server = server.clone();
+                let api = api.clone();
+                thread::spawn(move || {
+                    for request in server.incoming_requests() {
+                        api.handle(request);
+                    }
+                })
+            })
+            .collect())
+    }
+
+    fn handle(&self, request: Request) {
+        let reply = if *request.method() != Method::Get {
+            Reply::error(405, "only GET is supported")
+        } else {
+            let path = request.url().split('?').next().unwrap_or_default().to_string();
+            self.route(&path).unwrap_or_else(|e| {
+                log::error!("unable to serve {}: {:#}", path, e);
+                Reply::error(500, "internal error")
+            })
+        };
+        let content_type = Header::from_bytes("Content-Type", reply.content_type).expect("invalid header");
+        let response = Response::from_data(reply.body)
+            .with_status_code(reply.status)
+            .with_header(content_type);
+        if let Err(e) = request.respond(response) {
+            log::warn!("unable to send http response: {}", e);
+        }
+    }
+
+    fn route(&self, path: &str) -> anyhow::Result<Reply> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match segments[..] {
+            ["blocks", "slot", slot] => match slot.parse() {
+                Ok(slot) => self.block_at_slot(slot),
+                Err(_) => Ok(Reply::error(400, "invalid slot")),
+            },
+            ["blocks", hash] => self.artifact(self.archive.bodies.as_ref(), hash),
+            ["headers", hash] => self.artifact(self.archive.headers.as_ref(), hash),
+            ["tip"] => self.tip(),
+            ["cursors"] => self.cursors(),
+            ["metrics"] => Ok(self.metrics()),
+            _ => Ok(Reply::error(404, "not found")),
+        }
+    }
+
+    fn artifact(&self, storage: &dyn Storage, hash: &str) -> anyhow::Result<Reply> {
+        let Ok(hash) = hex::decode(hash) else { return Ok(Reply::error(400, "invalid hash")) };
+        let Some(slot) = self.index.slot(&hash)? else { return Ok(Reply::error(404, "not found")) };
+        match storage.read(&Point::Specific(slot, hash))? {
+            Some(bytes) => Ok(Reply::cbor(bytes)),
+            None => Ok(Reply::error(404, "not found")),
+        }
+    }
+
+    fn block_at_slot(&self, slot: u64) -> anyhow::Result<Reply> {
+        let bodies = self.archive.bodies.as_ref();
+        let points: Vec<Point> = bodies.points_before(slot).take_while(|p| p.slot_or_default() == slot).collect();
+        match &points[..] {
+            [] => Ok(Reply::error(404, "not found")),
+            [point] => match bodies.read(point)? {
+                Some(bytes) => Ok(Reply::cbor(bytes)),
+                None => Ok(Reply::error(404, "not found")),
+            },
+            // We saw more than one block at this slot, across forks, so let the caller pick
+            points => Ok(Reply::json(
+                300,
+                points
+                    .iter()
+                    .filter_map(|p| match p {
+                        Point::Specific(_, hash) => Some(json!({ "hash": hex::encode(hash), "url": format!("/blocks/{}", hex::encode(hash)) })),
+                        Point::Origin => None,
+                    })
+                    .collect(),
+            )),
+        }
+    }
+
+    /// The tip of the chain the index follows, as served to peers and replayed as events, or if nothing is indexed
+    /// yet, the newest block in the archive
+    fn tip(&self) -> anyhow::Result<Reply> {
+        let (point, block_number) = match self.index.tip()? {
+            Some(tip) => tip,
+            None => {
+                let bodies = self.archive.bodies.as_ref();
+                let Some(point) = bodies.before(u64::MAX) else { return Ok(Reply::error(404, "the archive is empty")) };
+                let Some(body) = bodies.read(&point)? else { return Ok(Reply::error(404, "the archive is empty")) };
+                let block_number = MultiEraBlock::decode(&body)?.number();
+                (point, block_number)
+            }
+        };
+        let mut tip = point_json(&point);
+        tip["block_number"] = json!(block_number);
+        Ok(Reply::json(200, tip))
+    }
+
+    fn cursors(&self) -> anyhow::Result<Reply> {
+        let mut cursors = vec![(FRONTIER_NAME.to_string(), self.directory.join(FRONTIER_NAME))];
+        cursors.extend(relay_cursors(&self.directory)?);
+
+        let mut result = vec![];
+        for (relay, path) in cursors {
+            if !path.exists() {
+                continue;
+            }
+            let cursor = match Cursor::load(&path, &relay, self.magic) {
+                Ok(cursor) => cursor,
+                Err(e) => {
+                    log::warn!("unable to read cursor {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let points: Vec<Value> = cursor.points.into_iter().map(|p| point_json(&p.into())).collect();
+            result.push(json!({
+                "relay": relay,
+                "points": points,
+                "tip": cursor.tip.map(|tip| json!({
+                    "point": point_json(&Point::from(tip.point)),
+                    "block_number": tip.block_number,
+                })),
+                "updated_at": cursor.updated_at,
+            }));
+        }
+        Ok(Reply::json(200, Value::Array(result)))
+    }
+
+    fn metrics(&self) -> Reply {
+        let peers: Vec<_> = {
+            let peers = self.peers.lock().expect("unable to acquire lock");
+            let mut peers: Vec<_> = peers.iter().map(|(address, stats)| (*address, stats.clone())).collect();
+            peers.sort_by_key(|(address, _)| *address);
+            peers
+        };
+        let mut body = String::new();
+        for (name, kind, help, value) in PEER_METRICS {
+            body.push_str(&format!("# HELP cardano_slurp_peer_{} {}\n# TYPE cardano_slurp_peer_{} {}\n", name, help, name, kind));
+            for (address, stats) in &peers {
+                let stats = stats.lock().expect("unable to acquire lock");
+                body.push_str(&format!("cardano_slurp_peer_{}{{peer=\"{}\"}} {}\n", name, address, value(&stats)));
+            }
+        }
+        Reply { status: 200, content_type: "text/plain; version=0.0.4", body: body.into_bytes() }
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context};
+use pallas::{
+    ledger::traverse::{MultiEraBlock, MultiEraHeader},
+    network::miniprotocols::Point,
+};
+
+use crate::{
+    body_slurp::BodySlurp,
+    cursor::{Cursor, FRONTIER_NAME},
+    network::Network,
+    storage::Archive,
+    utils::{extract_header, header_range, previous_hash},
+};
+
+// [ImmutableDB]: A node keeps the immutable part of the chain in {immutable}/{chunk}.chunk files, each
+// holding the blocks from a range of slots back to back, as many as there are in a byron epoch. Each chunk has two index files alongside:
+// - {chunk}.secondary is a 56 byte entry per block, giving its offset into the chunk, the offset and
+//   size of its header within the block, a CRC32 of the block, its hash, and its slot (or epoch, for an EBB)
+// - {chunk}.primary is a version byte, followed by a big endian u32 per relative slot, giving the offset
+//   of that slot's entry in the secondary index; a slot is filled if the next offset is larger than its own
+// Relative slot 0 of every chunk is reserved for a byron epoch boundary block.
+
+/// The version of the primary index format we understand
+pub const PRIMARY_VERSION: u8 = 1;
+/// The size in bytes of each entry in a secondary index
+pub const SECONDARY_ENTRY_SIZE: usize = 56;
+
+/// An entry from a chunk's secondary index, describing one block
+#[derive(Clone, Debug)]
+pub struct SecondaryEntry {
+    pub block_offset: u64,
+    pub header_offset: u16,
+    pub header_size: u16,
+    pub checksum: u32,
+    pub hash: [u8; 32],
+    pub slot_or_epoch: u64,
+}
+
+impl SecondaryEntry {
+    fn parse(bytes: &[u8]) -> Self {
+        SecondaryEntry {
+            block_offset: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            header_offset: u16::from_be_bytes(bytes[8..10].try_into().unwrap()),
+            header_size: u16::from_be_bytes(bytes[10..12].try_into().unwrap()),
+            checksum: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
+            hash: bytes[16..48].try_into().unwrap(),
+            slot_or_epoch: u64::from_be_bytes(bytes[48..56].try_into().unwrap()),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SECONDARY_ENTRY_SIZE);
+        bytes.extend_from_slice(&self.block_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.header_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.header_size.to_be_bytes());
+        bytes.extend_from_slice(&self.checksum.to_be_bytes());
+        bytes.extend_from_slice(&self.hash);
+        bytes.extend_from_slice(&self.slot_or_epoch.to_be_bytes());
+        bytes
+    }
+}
+
+/// A block read back out of a chunk file
+pub struct ChunkBlock {
+    pub point: Point,
+    pub header: Vec<u8>,
+    pub body: Vec<u8>,
+}
+
+/// List the chunk numbers in an ImmutableDB directory, in chain order
+pub fn chunk_numbers(immutable_directory: &Path) -> anyhow::Result<Vec<u64>> {
+    let mut chunks: Vec<u64> = fs::read_dir(immutable_directory)
+        .with_context(|| format!("unable to read immutable directory {:?}", immutable_directory))?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".chunk")?.parse::<u64>().ok())
+        .collect();
+    chunks.sort();
+    Ok(chunks)
+}
+
+pub fn chunk_path(immutable_directory: &Path, chunk: u64, extension: &str) -> PathBuf {
+    immutable_directory.join(format!("{:05}.{}", chunk, extension))
+}
+
+/// Read the secondary index entries for the filled slots of a chunk, along with whether each is an EBB
+fn read_index(immutable_directory: &Path, chunk: u64) -> anyhow::Result<Vec<(bool, SecondaryEntry)>> {
+    let primary_path = chunk_path(immutable_directory, chunk, "primary");
+    let primary = fs::read(&primary_path).with_context(|| format!("unable to read {:?}", primary_path))?;
+    let secondary_path = chunk_path(immutable_directory, chunk, "secondary");
+    let secondary = fs::read(&secondary_path).with_context(|| format!("unable to read {:?}", secondary_path))?;
+
+    match primary.first() {
+        Some(&PRIMARY_VERSION) => {}
+        Some(v) => bail!("unsupported primary index version {} in {:?}", v, primary_path),
+        None => bail!("empty primary index {:?}", primary_path),
+    }
+    let offsets: Vec<usize> = primary[1..]
+        .chunks_exact(4)
+        .map(|o| u32::from_be_bytes(o.try_into().unwrap()) as usize)
+        .collect();
+
+    let mut entries = vec![];
+    for (relative_slot, window) in offsets.windows(2).enumerate() {
+        let (offset, next) = (window[0], window[1]);
+        if next <= offset {
+            continue;
+        }
+        if offset + SECONDARY_ENTRY_SIZE > secondary.len() {
+            bail!("primary index {:?} points past the end of the secondary index", primary_path);
+        }
+        entries.push((relative_slot == 0, SecondaryEntry::parse(&secondary[offset..offset + SECONDARY_ENTRY_SIZE])));
+    }
+    Ok(entries)
+}
+
+/// Read every block in a chunk, checking each against its entry in the secondary index
+pub fn read_chunk(immutable_directory: &Path, chunk: u64, chunk_slots: u64) -> anyhow::Result<Vec<ChunkBlock>> {
+    let entries = read_index(immutable_directory, chunk)?;
+    let chunk_file = chunk_path(immutable_directory, chunk, "chunk");
+    let bytes = fs::read(&chunk_file).with_context(|| format!("unable to read {:?}", chunk_file))?;
+
+    let mut blocks = vec![];
+    for (idx, (is_ebb, entry)) in entries.iter().enumerate() {
+        // Blocks are stored back to back, so each one runs until the next begins
+        let start = entry.block_offset as usize;
+        let end = entries.get(idx + 1).map(|(_, e)| e.block_offset as usize).unwrap_or(bytes.len());
+        if start > end || end > bytes.len() {
+            bail!("block at offset {} runs past the end of {:?}", start, chunk_file);
+        }
+        let body = bytes[start..end].to_vec();
+
+        if crc32fast::hash(&body) != entry.checksum {
+            bail!("checksum mismatch for block at offset {} of {:?}", start, chunk_file);
+        }
+        let Some(point) = BodySlurp::body_point(&body) else {
+            bail!("unrecognized block at offset {} of {:?}", start, chunk_file);
+        };
+        let Point::Specific(slot, hash) = &point else { unreachable!() };
+        let indexed_slot = if *is_ebb { entry.slot_or_epoch * chunk_slots } else { entry.slot_or_epoch };
+        if *slot != indexed_slot || hash[..] != entry.hash[..] {
+            bail!(
+                "block at offset {} of {:?} is {}/{}, but is indexed as {}/{}",
+                start,
+                chunk_file,
+                slot,
+                hex::encode(hash),
+                indexed_slot,
+                hex::encode(entry.hash)
+            );
+        }
+
+        let Some(header) = extract_header(&body).map(|h| h.to_vec()) else {
+            bail!("unable to extract header for block {:?}", point);
+        };
+        let header_start = entry.header_offset as usize;
+        if body.get(header_start..header_start + entry.header_size as usize) != Some(&header[..]) {
+            log::warn!("header of block {:?} isn't where {:?} says it is", point, chunk_file);
+        }
+        blocks.push(ChunkBlock { point, header, body });
+    }
+    Ok(blocks)
+}
+
+/// Copy every block from a node's ImmutableDB into the archive, advancing the frontier as we go
+///
+/// Blocks we already have are skipped, so an interrupted import can simply be run again.
+pub fn import(
+    immutable_directory: &Path,
+    directory: &Path,
+    archive: &Archive,
+    network: &Network,
+    frontier_mutex: Arc<Mutex<Cursor>>,
+) -> anyhow::Result<()> {
+    let chunks = chunk_numbers(immutable_directory)?;
+    if chunks.is_empty() {
+        bail!("no chunk files found in {:?}", immutable_directory);
+    }
+    log::info!("importing {} chunks from {:?}", chunks.len(), immutable_directory);
+
+    let mut imported = 0;
+    let mut skipped = 0;
+    for chunk in chunks {
+        let blocks = read_chunk(immutable_directory, chunk, network.byron_epoch_length)?;
+
+        // Each chunk's new blocks are saved, and indexed, together
+        let mut points = vec![];
+        let (mut headers, mut bodies) = (vec![], vec![]);
+        for block in blocks {
+            points.push(block.point.clone());
+            if archive.bodies.contains(&block.point) {
+                skipped += 1;
+            } else {
+                headers.push((block.point.clone(), block.header));
+                bodies.push((block.point, block.body));
+                imported += 1;
+            }
+        }
+        archive.headers.write_batch(&headers)?;
+        archive.bodies.write_batch(&bodies)?;
+
+        let mut frontier_gaurd = frontier_mutex.lock().expect("unable to acquire lock");
+        for point in &points {
+            frontier_gaurd.add_frontier_point(point.clone());
+        }
+        frontier_gaurd.save(&directory.join(FRONTIER_NAME))?;
+        drop(frontier_gaurd);
+
+        if let Some(last) = points.last() {
+            log::info!("imported chunk {} ({} blocks, up to {:?})", chunk, points.len(), last);
+        }
+    }
+    log::info!("imported {} blocks, skipping {} we already had", imported, skipped);
+    Ok(())
+}
+
+/// A block on its way into an exported chunk
+struct ExportBlock {
+    slot: u64,
+    hash: Vec<u8>,
+    is_ebb: bool,
+    body: Vec<u8>,
+}
+
+/// Write one chunk and its index files, from its blocks in chain order
+///
+/// Every chunk but the newest is finalized, so its primary index covers every slot in the chunk
+fn write_chunk(
+    output_directory: &Path,
+    chunk: u64,
+    chunk_slots: u64,
+    blocks: &[ExportBlock],
+    finalized: bool,
+) -> anyhow::Result<()> {
+    let mut chunk_bytes = vec![];
+    let mut secondary = vec![];
+    let mut filled = vec![];
+    for block in blocks {
+        let Some(header) = header_range(&block.body) else {
+            bail!("unable to find the header of block {}/{}", block.slot, hex::encode(&block.hash));
+        };
+        let entry = SecondaryEntry {
+            block_offset: chunk_bytes.len() as u64,
+            header_offset: header.start as u16,
+            header_size: header.len() as u16,
+            checksum: crc32fast::hash(&block.body),
+            hash: block.hash[..].try_into()?,
+            slot_or_epoch: if block.is_ebb { block.slot / chunk_slots } else { block.slot },
+        };
+        secondary.extend(entry.to_bytes());
+        chunk_bytes.extend_from_slice(&block.body);
+        filled.push(if block.is_ebb { 0 } else { block.slot - chunk * chunk_slots + 1 });
+    }
+
+    let relative_slots = match (finalized, filled.last()) {
+        (true, _) => chunk_slots + 1,
+        (false, Some(last)) => last + 1,
+        (false, None) => 0,
+    };
+    let mut primary = vec![PRIMARY_VERSION];
+    let mut offset = 0u32;
+    primary.extend(offset.to_be_bytes());
+    // Blocks are in chain order, so the filled slots come in the same order we write the offsets in
+    let mut filled = filled.into_iter().peekable();
+    for relative_slot in 0..relative_slots {
+        if filled.next_if_eq(&relative_slot).is_some() {
+            offset += SECONDARY_ENTRY_SIZE as u32;
+        }
+        primary.extend(offset.to_be_bytes());
+    }
+
+    fs::write(chunk_path(output_directory, chunk, "chunk"), chunk_bytes)?;
+    fs::write(chunk_path(output_directory, chunk, "secondary"), secondary)?;
+    fs::write(chunk_path(output_directory, chunk, "primary"), primary)?;
+    Ok(())
+}
+
+/// Write the chain ending at the newest block in the archive out as a cardano-node ImmutableDB
+///
+/// The chain is followed backwards from its tip by previous hash, so blocks from abandoned forks are
+/// left out. Chunks are written as we go, so only one chunk's worth of blocks is held in memory.
+///
+/// A node never rolls back its ImmutableDB, so the newest `k` blocks of the chain, which still could be,
+/// are left out too. The chain has to reach all the way back to genesis for a node to use it, so if the
+/// archive is missing blocks along the way, whatever was written is removed again and the export fails.
+pub fn export(archive: &Archive, network: &Network, output_directory: &Path) -> anyhow::Result<()> {
+    let chunk_slots = network.byron_epoch_length;
+    let bodies = archive.bodies.as_ref();
+    let Some(Point::Specific(_, tip_hash)) = bodies.before(u64::MAX) else {
+        bail!("there are no blocks in the archive to export");
+    };
+    fs::create_dir_all(output_directory)?;
+    if !chunk_numbers(output_directory)?.is_empty() {
+        bail!("{:?} already contains chunk files", output_directory);
+    }
+
+    let mut wanted = Some(tip_hash);
+    // How many more blocks to pass over from the tip before the chain is immutable
+    let mut volatile = network.security_parameter;
+    let mut current: Option<(u64, Vec<ExportBlock>)> = None;
+    // The chunk written before the current one, which follows it in the chain
+    let mut next_chunk: Option<u64> = None;
+    let mut exported = 0;
+    let mut last_slot = u64::MAX;
+
+    // Walk the archive from newest to oldest, picking out each block of the chain as we reach it
+    for point in bodies.points_before(u64::MAX) {
+        let Some(wanted_hash) = &wanted else { break };
+        let Point::Specific(slot, hash) = point.clone() else { continue };
+        if &hash != wanted_hash {
+            continue;
+        }
+        let Some(body) = bodies.read(&point)? else {
+            bail!("unable to read block {:?}", point);
+        };
+        let block = MultiEraBlock::decode(&body)?;
+        let is_ebb = matches!(block.header(), MultiEraHeader::EpochBoundary(_));
+        wanted = previous_hash(&block.header());
+        drop(block);
+        last_slot = slot;
+        if volatile > 0 {
+            volatile -= 1;
+            continue;
+        }
+
+        let chunk = slot / chunk_slots;
+        if let Some((previous_chunk, blocks)) = current.take_if(|(c, _)| *c != chunk) {
+            flush_chunk(output_directory, previous_chunk, chunk_slots, blocks, &mut next_chunk)?;
+        }
+        current.get_or_insert_with(|| (chunk, vec![])).1.push(ExportBlock { slot, hash, is_ebb, body });
+        exported += 1;
+    }
+    if let Some((chunk, blocks)) = current.take() {
+        flush_chunk(output_directory, chunk, chunk_slots, blocks, &mut next_chunk)?;
+    }
+
+    if let Some(missing) = wanted {
+        remove_chunks(output_directory)?;
+        bail!(
+            "the archive is missing block {} before slot {}, so the export doesn't reach back to genesis",
+            hex::encode(missing),
+            last_slot
+        );
+    }
+    if exported == 0 {
+        bail!("the archive's chain is no more than {} blocks long, so none of it is immutable yet", network.security_parameter);
+    }
+    log::info!("exported {} blocks to {:?}", exported, output_directory);
+    Ok(())
+}
+
+/// Write out a chunk whose blocks were collected newest first, filling in any empty chunks between it and the next
+fn flush_chunk(
+    output_directory: &Path,
+    chunk: u64,
+    chunk_slots: u64,
+    mut blocks: Vec<ExportBlock>,
+    next_chunk: &mut Option<u64>,
+) -> anyhow::Result<()> {
+    blocks.reverse();
+    write_chunk(output_directory, chunk, chunk_slots, &blocks, next_chunk.is_some())?;
+    if let Some(next) = *next_chunk {
+        for empty in chunk + 1..next {
+            write_chunk(output_directory, empty, chunk_slots, &[], true)?;
+        }
+    }
+    log::info!("exported chunk {} ({} blocks)", chunk, blocks.len());
+    *next_chunk = Some(chunk);
+    Ok(())
+}
+
+/// Remove the chunk and index files of a partial export
+fn remove_chunks(output_directory: &Path) -> anyhow::Result<()> {
+    for chunk in chunk_numbers(output_directory)? {
+        for extension in ["chunk", "primary", "secondary"] {
+            let path = chunk_path(output_directory, chunk, extension);
+            if path.exists() {
+                fs::remove_file(&path).with_context(|| format!("unable to remove {:?}", path))?;
+            }
+        }
+    }
+    Ok(())
+}
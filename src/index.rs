@@ -0,0 +1,440 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use pallas::{
+    ledger::traverse::{MultiEraBlock, MultiEraHeader},
+    network::miniprotocols::Point,
+};
+use redb::{Database, Durability, MultimapTableDefinition, ReadableTable, ReadableTableMetadata, Table, TableDefinition, WriteTransaction};
+
+use crate::{
+    network::Network,
+    storage::{Archive, ArtifactLocation, Layout, Storage},
+    utils::{era_tag, previous_hash},
+};
+
+// [Index]: Alongside the artifacts themselves, we keep a redb database in {directory}/index.redb, so that
+// blocks can be looked up by more than just their point. It's kept up to date as artifacts are saved,
+// but it's only ever derived from the archive, so if it's lost or falls behind (say, after a crash), it
+// can be rebuilt from scratch with `reindex`.
+
+pub const INDEX_NAME: &str = "index.redb";
+
+/// The slot of every block we have a header or body for, by hash
+const SLOTS: TableDefinition<&[u8], u64> = TableDefinition::new("slots");
+/// The hashes of the blocks at each slot; there can be more than one after a fork
+const HASHES: MultimapTableDefinition<u64, &[u8]> = MultimapTableDefinition::new("hashes");
+/// The hash of the block at each height on the chain the index follows; see [Index::select_chain]
+const HEIGHTS: TableDefinition<u64, &[u8]> = TableDefinition::new("heights");
+/// For each block we have a body for, by hash: its slot, height, the era tag it's wrapped in (see
+/// [era_tag]), its size, and the hash of the block before it (empty for the first)
+const BLOCKS: TableDefinition<&[u8], BlockEntry> = TableDefinition::new("blocks");
+type BlockEntry<'a> = (u64, u64, u16, u64, &'a [u8]);
+/// The first and last slot we have a block at in each epoch, on networks we know the epochs of
+const EPOCHS: TableDefinition<u64, (u64, u64)> = TableDefinition::new("epochs");
+/// Where each header is kept, by hash: the path relative to the archive, then the offset and length
+const HEADER_LOCATIONS: TableDefinition<&[u8], (&str, u64, u64)> = TableDefinition::new("header_locations");
+/// Where each body is kept, by hash, in the same way as headers
+const BODY_LOCATIONS: TableDefinition<&[u8], (&str, u64, u64)> = TableDefinition::new("body_locations");
+
+/// The block each transaction is in, by transaction hash: the slot and hash of the block, then the
+/// transaction's position in it; only kept with `--index-transactions`, and if the same transaction made
+/// it into more than one block across a fork, the last one we saved
+const TRANSACTIONS: TableDefinition<&[u8], (u64, &[u8], u32)> = TableDefinition::new("transactions");
+
+/// What the index knows about a block we have the body of
+pub struct BlockInfo {
+    pub slot: u64,
+    pub height: u64,
+    pub era: u16,
+    pub size: u64,
+    pub previous: Option<Vec<u8>>,
+}
+
+/// Which kind of artifact a storage holds, and so what there is to index about it
+#[derive(Clone, Copy)]
+pub enum Artifact {
+    Header,
+    Body,
+}
+
+pub struct Index {
+    database: Database,
+    directory: PathBuf,
+    network: Option<&'static Network>,
+    /// Whether to index the transactions in each block too, which means decoding all of them
+    transactions: bool,
+}
+
+impl Index {
+    /// Open the index of an archive, creating an empty one if there isn't one yet
+    pub fn open(directory: &Path, magic: u64, transactions: bool) -> anyhow::Result<Index> {
+        let path = directory.join(INDEX_NAME);
+        let database = Database::create(&path).with_context(|| format!("unable to open index {:?}", path))?;
+        let index = Index {
+            database,
+            directory: directory.to_path_buf(),
+            network: Network::from_magic(magic),
+            transactions,
+        };
+        // Create every table up front, so readers never have to worry about one not existing yet
+        index.write(Durability::Immediate, |_| Ok(()))?;
+        Ok(index)
+    }
+
+    /// Whether no bodies have been indexed yet
+    pub fn is_empty(&self) -> anyhow::Result<bool> {
+        let transaction = self.database.begin_read()?;
+        // Indexes from before we kept BLOCKS have slots but nothing else, and need rebuilding just the same
+        Ok(transaction.open_table(BLOCKS)?.is_empty()?)
+    }
+
+    /// The slot of the block with the given hash, if we've saved its header or body
+    pub fn slot(&self, hash: &[u8]) -> anyhow::Result<Option<u64>> {
+        let transaction = self.database.begin_read()?;
+        Ok(transaction.open_table(SLOTS)?.get(hash)?.map(|slot| slot.value()))
+    }
+
+    pub fn block(&self, hash: &[u8]) -> anyhow::Result<Option<BlockInfo>> {
+        let transaction = self.database.begin_read()?;
+        let Some(entry) = transaction.open_table(BLOCKS)?.get(hash)? else { return Ok(None) };
+        let (slot, height, era, size, previous) = entry.value();
+        let previous = (!previous.is_empty()).then(|| previous.to_vec());
+        Ok(Some(BlockInfo { slot, height, era, size, previous }))
+    }
+
+    /// The block with the lowest height above `height` (or the lowest of all, for None), and its height
+    pub fn next_height(&self, height: Option<u64>) -> anyhow::Result<Option<(u64, Vec<u8>)>> {
+        let transaction = self.database.begin_read()?;
+        let heights = transaction.open_table(HEIGHTS)?;
+        let mut range = match height {
+            Some(height) if height == u64::MAX => return Ok(None),
+            Some(height) => heights.range(height + 1..)?,
+            None => heights.range(0..)?,
+        };
+        let Some(entry) = range.next() else { return Ok(None) };
+        let (height, hash) = entry?;
+        Ok(Some((height.value(), hash.value().to_vec())))
+    }
+
+    /// The highest block we have, and its height
+    pub fn highest(&self) -> anyhow::Result<Option<(u64, Vec<u8>)>> {
+        let transaction = self.database.begin_read()?;
+        let heights = transaction.open_table(HEIGHTS)?;
+        let Some((height, hash)) = heights.last()? else { return Ok(None) };
+        Ok(Some((height.value(), hash.value().to_vec())))
+    }
+
+    /// The point and height of the tip of the chain the index follows: the highest block we have
+    pub fn tip(&self) -> anyhow::Result<Option<(Point, u64)>> {
+        let Some((height, hash)) = self.highest()? else { return Ok(None) };
+        let Some(block) = self.block(&hash)? else { return Ok(None) };
+        Ok(Some((Point::Specific(block.slot, hash), height)))
+    }
+
+    /// The block after `point` on the chain the index follows, back from its tip, or None if `point` is the
+    /// tip, or isn't on the chain
+    pub fn successor(&self, point: &Point) -> anyhow::Result<Option<Point>> {
+        let (height, hash) = match point {
+            Point::Origin => (None, None),
+            Point::Specific(_, hash) => match self.block(hash)? {
+                Some(block) => (Some(block.height), Some(hash)),
+                None => return Ok(None),
+            },
+        };
+        // Epoch boundary blocks don't have a height of their own, so the next block by height may be one
+        // step further on than we're after; follow it back until we find `point`
+        let Some((_, mut walker)) = self.next_height(height)? else { return Ok(None) };
+        let mut child = None;
+        loop {
+            let Some(block) = self.block(&walker)? else { return Ok(None) };
+            if Some(&walker) == hash {
+                return Ok(child);
+            }
+            if height.is_some_and(|h| block.height < h) {
+                return Ok(None);
+            }
+            child = Some(Point::Specific(block.slot, walker));
+            match block.previous {
+                Some(previous) => walker = previous,
+                None if point == &Point::Origin => return Ok(child),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Whether `point` is on the chain the index follows
+    pub fn is_on_chain(&self, point: &Point) -> anyhow::Result<bool> {
+        let tip = self.tip()?.map(|(tip, _)| tip).unwrap_or(Point::Origin);
+        Ok(tip == *point || self.successor(point)?.is_some())
+    }
+
+    /// The last point on the chain the index follows at or before `point`, following it back through the blocks
+    /// before it, or Origin if they never meet
+    pub fn intersection(&self, point: &Point) -> anyhow::Result<Point> {
+        let mut walker = point.clone();
+        while let Point::Specific(_, hash) = &walker {
+            if self.is_on_chain(&walker)? {
+                break;
+            }
+            walker = match self.block(hash)?.and_then(|b| b.previous) {
+                Some(previous) => match self.block(&previous)? {
+                    Some(block) => Point::Specific(block.slot, previous),
+                    None => Point::Origin,
+                },
+                None => Point::Origin,
+            };
+        }
+        Ok(walker)
+    }
+
+    /// The point of the block a transaction is in, and where in the block it is
+    pub fn transaction(&self, hash: &[u8]) -> anyhow::Result<Option<(Point, u32)>> {
+        let transaction = self.database.begin_read()?;
+        let Some(entry) = transaction.open_table(TRANSACTIONS)?.get(hash)? else { return Ok(None) };
+        let (slot, block_hash, position) = entry.value();
+        Ok(Some((Point::Specific(slot, block_hash.to_vec()), position)))
+    }
+
+    fn write(
+        &self,
+        durability: Durability,
+        f: impl FnOnce(&WriteTransaction) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.database.begin_write()?;
+        transaction.set_durability(durability);
+        transaction.open_table(SLOTS)?;
+        transaction.open_multimap_table(HASHES)?;
+        transaction.open_table(HEIGHTS)?;
+        transaction.open_table(EPOCHS)?;
+        transaction.open_table(BLOCKS)?;
+        transaction.open_table(HEADER_LOCATIONS)?;
+        transaction.open_table(BODY_LOCATIONS)?;
+        transaction.open_table(TRANSACTIONS)?;
+        f(&transaction)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Index artifacts that were just saved, along with where each one was saved
+    ///
+    /// Each batch, whether a single artifact or a range of blocks fetched together, is committed in one
+    /// transaction, without waiting for it to reach the disk, as there are far too many to wait on; a crash
+    /// can lose the last few, which `reindex` will bring back.
+    pub fn record(&self, artifact: Artifact, artifacts: Vec<(&Point, &[u8], Option<ArtifactLocation>)>) -> anyhow::Result<()> {
+        self.write(Durability::Eventual, |transaction| {
+            for (point, bytes, location) in artifacts {
+                self.insert(transaction, artifact, point, bytes, location)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn insert(
+        &self,
+        transaction: &WriteTransaction,
+        artifact: Artifact,
+        point: &Point,
+        bytes: &[u8],
+        location: Option<ArtifactLocation>,
+    ) -> anyhow::Result<()> {
+        let Point::Specific(slot, hash) = point else { return Ok(()) };
+        transaction.open_table(SLOTS)?.insert(&hash[..], slot)?;
+        transaction.open_multimap_table(HASHES)?.insert(slot, &hash[..])?;
+
+        let locations = match artifact {
+            Artifact::Header => HEADER_LOCATIONS,
+            Artifact::Body => {
+                self.insert_block(transaction, *slot, hash, bytes)?;
+                BODY_LOCATIONS
+            }
+        };
+        if let Some(location) = location {
+            let path = location.path.strip_prefix(&self.directory).unwrap_or(&location.path);
+            let path = path.to_str().context("artifact path isn't valid unicode")?;
+            transaction
+                .open_table(locations)?
+                .insert(&hash[..], (path, location.offset, location.length))?;
+        }
+        Ok(())
+    }
+
+    /// Index what we can only learn from the block itself
+    fn insert_block(&self, transaction: &WriteTransaction, slot: u64, hash: &[u8], body: &[u8]) -> anyhow::Result<()> {
+        let block = MultiEraBlock::decode(body).with_context(|| format!("unable to decode block {}/{}", slot, hex::encode(hash)))?;
+        let era = era_tag(body).context("block has no era tag")?;
+        let previous = previous_hash(&block.header()).unwrap_or_default();
+        transaction
+            .open_table(BLOCKS)?
+            .insert(hash, (slot, block.number(), era, body.len() as u64, &previous[..]))?;
+        // An epoch boundary block has the same height as the block before it, so it would displace it
+        if !matches!(block.header(), MultiEraHeader::EpochBoundary(_)) {
+            Self::select_chain(transaction, hash, block.number())?;
+        }
+        if self.transactions {
+            let mut transactions = transaction.open_table(TRANSACTIONS)?;
+            for (position, tx) in block.txs().iter().enumerate() {
+                transactions.insert(&tx.hash()[..], (slot, hash, position as u32))?;
+            }
+        }
+        drop(block);
+
+        if let Some(network) = self.network {
+            let epoch = network.slot_epoch(slot);
+            let mut epochs = transaction.open_table(EPOCHS)?;
+            let range = epochs.get(epoch)?.map(|r| r.value());
+            let (first, last) = range.unwrap_or((slot, slot));
+            epochs.insert(epoch, (first.min(slot), last.max(slot)))?;
+        }
+        Ok(())
+    }
+
+    /// Make a block we've just saved part of the chain the index follows, if it extends it or fills a gap in it
+    ///
+    /// The chain is the longest one we have, so a block on a fork that's no longer than it, as a peer sends
+    /// after rolling back, leaves it be; once the fork grows past the chain's tip, it takes over every height
+    /// back to where it branched off.
+    fn select_chain(transaction: &WriteTransaction, hash: &[u8], height: u64) -> anyhow::Result<()> {
+        let mut heights = transaction.open_table(HEIGHTS)?;
+        let blocks = transaction.open_table(BLOCKS)?;
+        let highest = heights.last()?.map(|(height, _)| height.value());
+        let selected = match highest {
+            Some(highest) if height <= highest => {
+                // Bodies can arrive out of order, from several peers at once, so the block after this one on
+                // the chain may already be there
+                let child = heights.get(height + 1)?.map(|hash| hash.value().to_vec());
+                match child {
+                    Some(child) => Self::parent(&blocks, &child)?.as_deref() == Some(hash),
+                    None => false,
+                }
+            }
+            _ => true,
+        };
+        if !selected {
+            return Ok(());
+        }
+
+        let mut walker = hash.to_vec();
+        loop {
+            let Some(entry) = blocks.get(&walker[..])? else { break };
+            let (_, height, era, _, previous) = entry.value();
+            // Epoch boundary blocks don't have a height of their own; step over them
+            if era != 0 {
+                if heights.get(height)?.is_some_and(|hash| hash.value() == &walker[..]) {
+                    break;
+                }
+                heights.insert(height, &walker[..])?;
+            }
+            if previous.is_empty() {
+                break;
+            }
+            walker = previous.to_vec();
+        }
+        Ok(())
+    }
+
+    /// The block before the one with the given hash, stepping over an epoch boundary block in between
+    fn parent(blocks: &Table<&[u8], BlockEntry>, hash: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut walker = hash.to_vec();
+        loop {
+            let Some(entry) = blocks.get(&walker[..])? else { return Ok(None) };
+            let (_, _, _, _, previous) = entry.value();
+            if previous.is_empty() {
+                return Ok(None);
+            }
+            let previous = previous.to_vec();
+            let is_boundary = blocks.get(&previous[..])?.is_some_and(|entry| entry.value().2 == 0);
+            if !is_boundary {
+                return Ok(Some(previous));
+            }
+            walker = previous;
+        }
+    }
+}
+
+/// A storage that indexes everything saved to it; see [Index]
+pub struct IndexedStorage {
+    inner: Arc<dyn Storage>,
+    index: Arc<Index>,
+    artifact: Artifact,
+}
+
+impl IndexedStorage {
+    pub fn new(inner: Arc<dyn Storage>, index: Arc<Index>, artifact: Artifact) -> Self {
+        Self { inner, index, artifact }
+    }
+}
+
+impl Storage for IndexedStorage {
+    fn write(&self, point: &Point, bytes: &[u8]) -> anyhow::Result<()> {
+        self.inner.write(point, bytes)?;
+        self.index.record(self.artifact, vec![(point, bytes, self.inner.location(point))])
+    }
+
+    fn write_batch(&self, artifacts: &[(Point, Vec<u8>)]) -> anyhow::Result<()> {
+        self.inner.write_batch(artifacts)?;
+        let located = artifacts
+            .iter()
+            .map(|(point, bytes)| (point, &bytes[..], self.inner.location(point)))
+            .collect();
+        self.index.record(self.artifact, located)
+    }
+
+    fn read(&self, point: &Point) -> anyhow::Result<Option<Vec<u8>>> {
+        self.inner.read(point)
+    }
+
+    fn contains(&self, point: &Point) -> bool {
+        self.inner.contains(point)
+    }
+
+    fn location(&self, point: &Point) -> Option<ArtifactLocation> {
+        self.inner.location(point)
+    }
+
+    fn points_before(&self, max_slot: u64) -> Box<dyn Iterator<Item = Point> + '_> {
+        self.inner.points_before(max_slot)
+    }
+
+    fn points(&self) -> Box<dyn Iterator<Item = Point> + '_> {
+        self.inner.points()
+    }
+}
+
+/// How many artifacts to index in each transaction while rebuilding
+const REINDEX_BATCH_SIZE: usize = 10_000;
+
+/// Throw away the index of an archive, and build it again from every artifact in the archive
+pub fn reindex(directory: &Path, layout: Layout, magic: u64, transactions: bool) -> anyhow::Result<()> {
+    let path = directory.join(INDEX_NAME);
+    if path.exists() {
+        fs::remove_file(&path).with_context(|| format!("unable to remove index {:?}", path))?;
+    }
+    let archive = Archive::open(directory, layout)?;
+    let index = Index::open(directory, magic, transactions)?;
+
+    for (artifact, kind, storage) in [
+        (Artifact::Header, "headers", &archive.headers),
+        (Artifact::Body, "bodies", &archive.bodies),
+    ] {
+        let mut points = storage.points().peekable();
+        let mut indexed = 0;
+        while points.peek().is_some() {
+            index.write(Durability::Immediate, |transaction| {
+                for point in points.by_ref().take(REINDEX_BATCH_SIZE) {
+                    let Some(bytes) = storage.read(&point)? else { continue };
+                    index.insert(transaction, artifact, &point, &bytes, storage.location(&point))?;
+                    indexed += 1;
+                }
+                Ok(())
+            })?;
+            log::info!("indexed {} {}", indexed, kind);
+        }
+    }
+    log::info!("done; the index is in {:?}", path);
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use pallas::{
+    codec::minicbor::{decode, encode, Decode, Decoder, Encode, Encoder},
+    network::multiplexer::{agents::ChannelBuffer, StdChannel},
+};
+
+use crate::stats::PeerStats;
+
+/// The node-to-node mini-protocol number for keep-alive
+pub const KEEPALIVE_CHANNEL: u16 = 8;
+
+/// The messages of the keep-alive mini-protocol
+enum Message {
+    /// Asks the peer to echo the cookie back
+    KeepAlive(u16),
+    /// The cookie from the keep-alive being answered
+    Response(u16),
+    Done,
+}
+
+impl Encode<()> for Message {
+    fn encode<W: encode::Write>(&self, e: &mut Encoder<W>, _ctx: &mut ()) -> Result<(), encode::Error<W::Error>> {
+        match self {
+            Message::KeepAlive(cookie) => e.array(2)?.u16(0)?.u16(*cookie)?,
+            Message::Response(cookie) => e.array(2)?.u16(1)?.u16(*cookie)?,
+            Message::Done => e.array(1)?.u16(2)?,
+        };
+        Ok(())
+    }
+}
+
+impl<'b> Decode<'b, ()> for Message {
+    fn decode(d: &mut Decoder<'b>, _ctx: &mut ()) -> Result<Self, decode::Error> {
+        d.array()?;
+        match d.u16()? {
+            0 => Ok(Message::KeepAlive(d.u16()?)),
+            1 => Ok(Message::Response(d.u16()?)),
+            2 => Ok(Message::Done),
+            _ => Err(decode::Error::message("unknown keep-alive message")),
+        }
+    }
+}
+
+/// Sends the peer a keep-alive every `interval`, so relays don't drop connections that are idle at the tip, and
+/// records how long each takes to come back
+///
+/// The thread is left to finish on its own once the connection closes, which it notices the next time it sends.
+pub fn spawn(relay: String, channel: StdChannel, interval: Duration, stats_mutex: Arc<Mutex<PeerStats>>) {
+    thread::spawn(move || {
+        if let Err(e) = keep_alive(channel, interval, &stats_mutex) {
+            log::info!(target: &relay, "stopped sending keep-alives: {}", e);
+        }
+    });
+}
+
+fn keep_alive(channel: StdChannel, interval: Duration, stats_mutex: &Mutex<PeerStats>) -> anyhow::Result<()> {
+    let mut buffer = ChannelBuffer::new(channel);
+    let mut cookie: u16 = 0;
+    loop {
+        thread::sleep(interval);
+        cookie = cookie.wrapping_add(1);
+        let sent = Instant::now();
+        stats_mutex.lock().expect("unable to acquire lock").keepalive_sent();
+        buffer.send_msg_chunks(&Message::KeepAlive(cookie))?;
+        match buffer.recv_full_msg()? {
+            Message::Response(echoed) if echoed == cookie => {
+                stats_mutex.lock().expect("unable to acquire lock").keepalive_answered(sent.elapsed());
+            }
+            Message::Response(echoed) => bail!("peer answered keep-alive {} with cookie {}", cookie, echoed),
+            _ => bail!("unexpected keep-alive message from peer"),
+        }
+    }
+}
+
+/// Answers a peer's keep-alives until it's done with them, or the connection closes
+pub fn respond(channel: StdChannel) -> anyhow::Result<()> {
+    let mut buffer = ChannelBuffer::new(channel);
+    loop {
+        match buffer.recv_full_msg()? {
+            Message::KeepAlive(cookie) => buffer.send_msg_chunks(&Message::Response(cookie))?,
+            Message::Done => return Ok(()),
+            Message::Response(_) => bail!("unexpected keep-alive response from peer"),
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::bail;
+use pallas::network::{
+    miniprotocols::{chainsync, handshake, Point, MAINNET_MAGIC},
+    multiplexer::{bearers::Bearer, StdChannel, StdPlexer},
+};
+
+use crate::{body_slurp::BodySlurp, cursor::Cursor, events::{Event, Events}, header_slurp::HeaderSlurp, storage::Archive};
+
+/// The name we keep the cursor for the local node under
+pub const LOCAL_NAME: &str = "local";
+
+/// Slurps blocks from a cardano-node we run alongside, over its local socket
+///
+/// The node-to-client chainsync protocol delivers whole blocks rather than headers, so there's no
+/// need for a separate blockfetch; we slice the header out of each block and save both, just as
+/// we would if we had downloaded them from a relay.
+pub struct LocalSlurp {
+    pub directory: PathBuf,
+    pub socket: PathBuf,
+    pub magic: Option<u64>,
+
+    archive: Archive,
+    cursor_mutex: Arc<Mutex<Cursor>>,
+    frontier_mutex: Arc<Mutex<Cursor>>,
+    events: Arc<Events>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl LocalSlurp {
+    pub fn new(
+        directory: PathBuf,
+        archive: Archive,
+        socket: PathBuf,
+        default_point: Option<Point>,
+        magic: Option<u64>,
+        frontier_mutex: Arc<Mutex<Cursor>>,
+        events: Arc<Events>,
+    ) -> Self {
+        let cursor = Cursor::resume(&directory, LOCAL_NAME, magic.unwrap_or(MAINNET_MAGIC), &frontier_mutex, default_point)
+            .expect("unable to load cursor file");
+
+        Self {
+            directory,
+            archive,
+            socket,
+            magic,
+            cursor_mutex: Arc::new(Mutex::new(cursor)),
+            frontier_mutex,
+            events,
+            join_handle: None,
+        }
+    }
+
+    fn do_handshake(&self, channel: StdChannel) -> anyhow::Result<()> {
+        let mut client = handshake::N2CClient::new(channel);
+
+        let confirmation = client
+            .handshake(handshake::n2c::VersionTable::v1_and_above(self.magic.unwrap_or(MAINNET_MAGIC)))?;
+
+        match confirmation {
+            handshake::Confirmation::Accepted(v, _) => {
+                log::info!(target: LOCAL_NAME, "hand-shake accepted, using version {}", v);
+                Ok(())
+            }
+            handshake::Confirmation::Rejected(x) => {
+                bail!("hand-shake rejected with reason {:?}", x)
+            }
+        }
+    }
+
+    pub fn slurp(&mut self) -> anyhow::Result<()> {
+        log::info!(target: LOCAL_NAME, "starting slurp from {:?}", self.socket);
+
+        let bearer = Bearer::connect_unix(&self.socket)?;
+
+        // node-to-client uses a different set of mini-protocol IDs to node-to-node
+        let mut plexer = StdPlexer::new(bearer);
+        let channel0 = plexer.use_channel(0);
+        let channel5 = plexer.use_channel(5);
+
+        plexer.muxer.spawn();
+        plexer.demuxer.spawn();
+
+        self.do_handshake(channel0)?;
+
+        let known_points = self
+            .cursor_mutex
+            .lock()
+            .expect("unable to acquire lock")
+            .intersection_candidates(self.archive.bodies.as_ref());
+
+        let mut client = chainsync::N2CClient::new(channel5);
+        let (point, tip) = client.find_intersect(known_points)?;
+        let Some(point) = point else {
+            bail!("no intersection found with local node, whose tip is {:?}", tip.0);
+        };
+        log::info!(target: LOCAL_NAME, "intersected point is {:?}", point);
+
+        let directory = self.directory.clone();
+        let archive = self.archive.clone();
+        let cursor_mutex = self.cursor_mutex.clone();
+        let frontier_mutex = self.frontier_mutex.clone();
+        let events = self.events.clone();
+        self.join_handle = Some(thread::spawn(move || loop {
+            let next = if client.has_agency() {
+                client.request_next()
+            } else {
+                client.recv_while_can_await()
+            };
+            let next = match next {
+                Ok(next) => next,
+                Err(e) => {
+                    log::warn!(target: LOCAL_NAME, "chainsync failed: {}", e);
+                    break;
+                }
+            };
+
+            match next {
+                chainsync::NextResponse::RollForward(block, tip) => {
+                    cursor_mutex.lock().expect("unable to acquire lock").tip = Some(tip.into());
+                    let body: Vec<u8> = block.into();
+                    let header = crate::utils::extract_header(&body).map(|h| h.to_vec());
+                    let point = BodySlurp::handle_body(
+                        cursor_mutex.clone(),
+                        frontier_mutex.clone(),
+                        &events,
+                        LOCAL_NAME,
+                        &directory,
+                        archive.bodies.as_ref(),
+                        body,
+                    );
+                    match header {
+                        Some(header) if HeaderSlurp::header_point(&header).as_ref() == Some(&point) => {
+                            archive.headers.write(&point, &header).expect("could not save header");
+                        }
+                        _ => log::warn!(target: LOCAL_NAME, "unable to extract header for block {:?}", point),
+                    }
+                }
+                chainsync::NextResponse::RollBackward(rollback_to, tip) => {
+                    cursor_mutex.lock().expect("unable to acquire lock").tip = Some(tip.into());
+                    log::info!(target: LOCAL_NAME, "rollback to {:?}", rollback_to);
+                    events.publish(Event::RollBackward { relay: LOCAL_NAME.to_string(), point: rollback_to });
+                }
+                chainsync::NextResponse::Await => {
+                    log::info!(target: LOCAL_NAME, "tip of chain reached");
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    pub fn join(&mut self) -> thread::Result<()> {
+        match self.join_handle.take() {
+            Some(jh) => jh.join(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        net::Shutdown,
+        os::unix::net::UnixListener,
+        path::Path,
+    };
+
+    use pallas::{
+        codec::minicbor::{data::Tag, decode, encode, Decode, Decoder, Encode, Encoder},
+        network::{
+            miniprotocols::chainsync::{SkippedContent, Tip},
+            multiplexer::agents::ChannelBuffer,
+        },
+    };
+
+    use super::*;
+    use crate::{
+        cursor::FRONTIER_NAME,
+        storage::Layout,
+        testing::{ebb_chain, TempDir},
+    };
+
+    /// A block as node-to-client chainsync sends it, wrapped up as embedded CBOR
+    struct ServedBlock(Vec<u8>);
+
+    impl Encode<()> for ServedBlock {
+        fn encode<W: encode::Write>(&self, e: &mut Encoder<W>, _ctx: &mut ()) -> Result<(), encode::Error<W::Error>> {
+            e.tag(Tag::Cbor)?.bytes(&self.0)?;
+            Ok(())
+        }
+    }
+
+    impl<'b> Decode<'b, ()> for ServedBlock {
+        fn decode(d: &mut Decoder<'b>, _ctx: &mut ()) -> Result<Self, decode::Error> {
+            d.tag()?;
+            Ok(ServedBlock(d.bytes()?.to_vec()))
+        }
+    }
+
+    /// The versions a client proposes in a node-to-client hand-shake
+    struct Proposal(Vec<u64>);
+
+    impl<'b> Decode<'b, ()> for Proposal {
+        fn decode(d: &mut Decoder<'b>, _ctx: &mut ()) -> Result<Self, decode::Error> {
+            d.array()?;
+            d.u16()?;
+            let mut versions = vec![];
+            for _ in 0..d.map()?.unwrap_or_default() {
+                versions.push(d.u64()?);
+                d.skip()?;
+            }
+            Ok(Proposal(versions))
+        }
+    }
+
+    impl Encode<()> for Proposal {
+        fn encode<W: encode::Write>(&self, _e: &mut Encoder<W>, _ctx: &mut ()) -> Result<(), encode::Error<W::Error>> {
+            Err(encode::Error::message("only clients send proposals"))
+        }
+    }
+
+    /// Accepting a node-to-client hand-shake, at a version and network magic
+    struct Accept(u64, u64);
+
+    impl Encode<()> for Accept {
+        fn encode<W: encode::Write>(&self, e: &mut Encoder<W>, _ctx: &mut ()) -> Result<(), encode::Error<W::Error>> {
+            e.array(3)?.u16(1)?.u64(self.0)?.u64(self.1)?;
+            Ok(())
+        }
+    }
+
+    impl<'b> Decode<'b, ()> for Accept {
+        fn decode(_d: &mut Decoder<'b>, _ctx: &mut ()) -> Result<Self, decode::Error> {
+            Err(decode::Error::message("only nodes accept proposals"))
+        }
+    }
+
+    /// Stands in for a cardano-node's local socket, serving a recorded chain to a single client, then hanging up
+    /// once it's asked for more
+    fn mock_node(socket: &Path, blocks: Vec<Vec<u8>>) -> JoinHandle<()> {
+        let listener = UnixListener::bind(socket).unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut plexer = StdPlexer::new(Bearer::Unix(stream.try_clone().unwrap()));
+            let mut handshake = ChannelBuffer::new(plexer.use_channel(0x8000));
+            let mut chainsync = ChannelBuffer::new(plexer.use_channel(0x8000 | 5));
+            plexer.muxer.spawn();
+            plexer.demuxer.spawn();
+
+            let Proposal(versions) = handshake.recv_full_msg().unwrap();
+            handshake.send_msg_chunks(&Accept(versions.into_iter().max().unwrap(), MAINNET_MAGIC)).unwrap();
+
+            let points: Vec<Point> = blocks.iter().map(|b| BodySlurp::body_point(b).unwrap()).collect();
+            let tip = Tip(points.last().unwrap().clone(), points.len() as u64 - 1);
+            let mut position = 0;
+            let mut rollback = None;
+            loop {
+                let reply = match chainsync.recv_full_msg::<chainsync::Message<SkippedContent>>().unwrap() {
+                    chainsync::Message::FindIntersect(candidates) => {
+                        let found = candidates.into_iter().find_map(|candidate| match candidate {
+                            Point::Origin => Some((candidate, 0)),
+                            _ => points.iter().position(|p| *p == candidate).map(|i| (candidate, i + 1)),
+                        });
+                        match found {
+                            Some((point, next)) => {
+                                position = next;
+                                rollback = Some(point.clone());
+                                chainsync::Message::IntersectFound(point, tip.clone())
+                            }
+                            None => chainsync::Message::IntersectNotFound(tip.clone()),
+                        }
+                    }
+                    // Like a real node, the first reply after an intersection is to roll back to it
+                    chainsync::Message::RequestNext if rollback.is_some() => {
+                        chainsync::Message::RollBackward(rollback.take().unwrap(), tip.clone())
+                    }
+                    chainsync::Message::RequestNext if position < blocks.len() => {
+                        position += 1;
+                        chainsync::Message::RollForward(ServedBlock(blocks[position - 1].clone()), tip.clone())
+                    }
+                    _ => {
+                        let _ = stream.shutdown(Shutdown::Both);
+                        return;
+                    }
+                };
+                chainsync.send_msg_chunks(&reply).unwrap();
+            }
+        })
+    }
+
+    fn local_slurp(directory: &TempDir, default_point: Option<Point>) -> LocalSlurp {
+        let frontier = Cursor::new(FRONTIER_NAME.to_string(), MAINNET_MAGIC, VecDeque::new());
+        LocalSlurp::new(
+            directory.path().to_path_buf(),
+            Archive::open_raw(directory.path(), Layout::Files).unwrap(),
+            directory.path().join("node.socket"),
+            default_point,
+            None,
+            Arc::new(Mutex::new(frontier)),
+            Arc::new(Events::default()),
+        )
+    }
+
+    #[test]
+    fn slurps_every_block_from_the_node() {
+        let directory = TempDir::new("local");
+        let blocks = ebb_chain(5);
+        let node = mock_node(&directory.path().join("node.socket"), blocks.clone());
+
+        let mut local = local_slurp(&directory, None);
+        local.slurp().unwrap();
+        local.join().unwrap();
+        node.join().unwrap();
+
+        let archive = Archive::open_raw(directory.path(), Layout::Files).unwrap();
+        let points: Vec<Point> = blocks.iter().map(|b| BodySlurp::body_point(b).unwrap()).collect();
+        for (point, block) in points.iter().zip(&blocks) {
+            assert_eq!(archive.bodies.read(point).unwrap().as_ref(), Some(block));
+            assert!(archive.headers.read(point).unwrap().is_some());
+        }
+        let cursor = Cursor::load(&directory.path().join("cursors").join(LOCAL_NAME), LOCAL_NAME, MAINNET_MAGIC).unwrap();
+        assert_eq!(cursor.points.front().cloned().map(Point::from).as_ref(), points.last());
+    }
+
+    #[test]
+    fn fails_to_slurp_without_an_intersection() {
+        let directory = TempDir::new("local");
+        let _node = mock_node(&directory.path().join("node.socket"), ebb_chain(5));
+
+        let mut local = local_slurp(&directory, Some(Point::Specific(1234, vec![9; 32])));
+        let error = local.slurp().expect_err("slurped without an intersection");
+        assert!(error.to_string().contains("no intersection found"));
+    }
+}
@@ -1,16 +1,60 @@
-use std::fs;
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
 
-use clap::Parser;
-use slurp::Slurp;
-use topology::Topology;
+use args::{Command, ExportFormat};
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use pallas::network::miniprotocols::{Point, MAINNET_MAGIC};
+use compression::Compression;
+use events::{EventServer, Events};
+use http::HttpServer;
+use index::Index;
+use local_slurp::LocalSlurp;
+use manifest::Manifest;
+use nats::NatsSink;
+use network::Network;
+use pool::{Pool, RelayGroup};
+use resolver::SystemResolver;
+use server::Server;
+use stats::PeerStatsMap;
+use storage::{Archive, Layout};
+use topology::{Topology, TopologyWatcher};
+use webhooks::Webhooks;
 
 mod args;
 mod cursor;
 mod topology;
 mod body_slurp;
+mod compression;
+mod events;
 mod header_slurp;
+mod http;
+mod immutable_db;
+mod index;
+mod keepalive;
+mod local_slurp;
+mod manifest;
+mod nats;
+mod network;
+mod pool;
+mod resolver;
+mod server;
 mod slurp;
+mod stats;
+mod storage;
+#[cfg(test)]
+mod testing;
+mod transactions;
 mod utils;
+mod webhooks;
+
+/// How often we check for dropped connections, and try to reconnect them
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+/// How often we check whether the topology file has changed
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
 
 fn main() {
     let args = args::Args::parse();
@@ -19,30 +63,236 @@ fn main() {
         .filter_level(log::LevelFilter::Info)
         .init();
 
-    let mut connections = vec![];
+    let magic = args.testnet_magic.unwrap_or(MAINNET_MAGIC);
+    fs::create_dir_all(&args.directory).expect("unable to create directory");
+    let manifest = Manifest::load(&args.directory).expect("unable to read archive manifest");
+
+    let found_layout = manifest.as_ref().map(|m| m.layout).or_else(|| Layout::detect(&args.directory));
+    let layout = match (found_layout, args.layout) {
+        (Some(found), Some(wanted)) if found != wanted => args::Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!("the archive uses the {} layout; run `migrate --to {}` to change it", found, wanted),
+            )
+            .exit(),
+        (found, wanted) => found.or(wanted).unwrap_or(Layout::Files),
+    };
+    let archive = Archive::open(&args.directory, layout).expect("unable to open archive");
+    if let Some(Err(e)) = manifest.as_ref().map(|m| m.check(magic, archive.bodies.as_ref())) {
+        args::Args::command().error(ErrorKind::ArgumentConflict, format!("{:#}", e)).exit()
+    }
+    let mut manifest = manifest.unwrap_or_else(|| {
+        // Archives from before there were manifests are for whichever network they were synced from
+        Manifest::check_unrecorded(&args.directory, magic, archive.bodies.as_ref())
+            .unwrap_or_else(|e| args::Args::command().error(ErrorKind::ArgumentConflict, format!("{:#}", e)).exit());
+        let compression = Compression::load(&args.directory).expect("unable to load compression settings");
+        let manifest = Manifest::new(magic, layout, compression.summary()).expect("unable to create manifest");
+        manifest.save(&args.directory).expect("unable to write archive manifest");
+        log::info!("created archive manifest for network magic {}", magic);
+        manifest
+    });
+    if manifest.record_first_block(archive.bodies.as_ref()) {
+        manifest.save(&args.directory).expect("unable to write archive manifest");
+    }
+    let frontier = cursor::check_cursors(&args.directory, magic)
+        .and_then(|_| cursor::load_frontier(&args.directory, magic))
+        .unwrap_or_else(|e| args::Args::command().error(ErrorKind::ArgumentConflict, format!("{:#}", e)).exit());
+    let frontier_mutex = Arc::new(Mutex::new(frontier));
 
-    for relay in args.relay {
-        let slurp = Slurp::new(args.directory.clone(), relay, args.fallback_point.clone(), args.testnet_magic);
-        connections.push(slurp);
+    if args.index_transactions && !manifest.index_transactions {
+        manifest.index_transactions = true;
+        manifest.save(&args.directory).expect("unable to write archive manifest");
+        log::info!("indexing transactions from now on; run `reindex` to index those in blocks already in the archive");
+    }
+    let index_transactions = manifest.index_transactions;
+    if args.save_transactions && !manifest.save_transactions {
+        manifest.save_transactions = true;
+        manifest.save(&args.directory).expect("unable to write archive manifest");
+        log::info!("saving transactions from blocks slurped from now on");
     }
 
-    if let Some(topology_file) = args.topology_file {
-        let file_contents = fs::read_to_string(topology_file).expect("unable to open topology file");
-        let topology: Topology = serde_json::from_str(&file_contents).expect("unable to parse topology file");
-        for producer in topology.producers {
-            let url = format!("{}:{}", producer.address, producer.port);
-            let slurp = Slurp::new(args.directory.clone(), url, args.fallback_point.clone(), args.testnet_magic);
-            connections.push(slurp);
+    if let Some(Command::Migrate { to }) = &args.command {
+        drop(archive);
+        storage::migrate(&args.directory, *to).expect("unable to migrate archive");
+        manifest.layout = *to;
+        manifest.save(&args.directory).expect("unable to write archive manifest");
+        // Artifacts have all moved, so wherever the index says they are is out of date
+        index::reindex(&args.directory, *to, magic, index_transactions).expect("unable to reindex archive");
+        return;
+    }
+    if let Some(Command::Reindex) = &args.command {
+        index::reindex(&args.directory, layout, magic, index_transactions).expect("unable to reindex archive");
+        return;
+    }
+    if let Some(Command::Recompress { level, samples, dry_run }) = &args.command {
+        // Reindexing replaces the index file, so it mustn't be open meanwhile
+        drop(archive);
+        compression::recompress(&args.directory, layout, *level, *samples, *dry_run).expect("unable to recompress archive");
+        let compression = Compression::load(&args.directory).expect("unable to load compression settings");
+        manifest.compression = compression.summary();
+        manifest.save(&args.directory).expect("unable to write archive manifest");
+        if !*dry_run {
+            index::reindex(&args.directory, layout, magic, index_transactions).expect("unable to reindex archive");
+        }
+        return;
+    }
+    let index = Arc::new(Index::open(&args.directory, magic, index_transactions).expect("unable to open archive index"));
+    if let Some(Command::Transaction { hash }) = &args.command {
+        let Ok(hash) = hex::decode(hash) else {
+            args::Args::command().error(ErrorKind::ValueValidation, "the transaction hash isn't valid hex").exit()
+        };
+        match index.transaction(&hash).expect("unable to read archive index") {
+            Some((Point::Specific(slot, block_hash), position)) => println!("{}/{} {}", slot, hex::encode(block_hash), position),
+            _ if !index_transactions => args::Args::command()
+                .error(ErrorKind::InvalidValue, "transactions aren't indexed in this archive; see `--index-transactions`")
+                .exit(),
+            _ => {
+                log::error!("transaction {} isn't in the index", hex::encode(hash));
+                std::process::exit(1);
+            }
         }
+        return;
+    }
+    if index.is_empty().expect("unable to read archive index") && archive.bodies.points().next().is_some() {
+        log::warn!("the archive hasn't been indexed yet; run `reindex` to index the blocks already in it");
     }
+    let archive = archive.indexed(index.clone());
+    let archive = if manifest.save_transactions { archive.with_transactions(&args.directory) } else { archive };
 
-    for connection in connections.iter_mut() {
-        if let Err(e) = connection.slurp() {
-            log::warn!("connection refused from {}: {}", connection.relay, e);
+    match &args.command {
+        Some(Command::Import { immutable_directory }) => {
+            immutable_db::import(immutable_directory, &args.directory, &archive, immutable_network(magic), frontier_mutex)
+                .expect("unable to import blocks");
+            return;
         }
+        Some(Command::Export { format: ExportFormat::ImmutableDb, output_directory }) => {
+            immutable_db::export(&archive, immutable_network(magic), output_directory)
+                .unwrap_or_else(|e| args::Args::command().error(ErrorKind::InvalidValue, format!("{:#}", e)).exit());
+            return;
+        }
+        Some(Command::Migrate { .. })
+        | Some(Command::Reindex)
+        | Some(Command::Recompress { .. })
+        | Some(Command::Transaction { .. })
+        | None => {}
+    }
+
+    let fallback_point = args
+        .fallback_point
+        .as_ref()
+        .map(|spec| spec.resolve(archive.headers.as_ref(), magic))
+        .transpose()
+        .unwrap_or_else(|e| {
+            args::Args::command()
+                .error(ErrorKind::ValueValidation, format!("invalid value for '--fallback-point': {}", e))
+                .exit()
+        });
+
+    let mut events = Events::default();
+    if let Some(url) = &args.nats {
+        let sink = NatsSink::connect(url, &args.nats_subject)
+            .unwrap_or_else(|e| args::Args::command().error(ErrorKind::Io, format!("{:#}", e)).exit());
+        events.add_sink(Box::new(sink));
+    }
+    let events = Arc::new(events);
+    let webhooks = Arc::new(Webhooks::new(
+        args.webhook.clone(),
+        args.webhook_rollback_depth,
+        Duration::from_secs(args.webhook_stall_seconds),
+    ));
+    if args.events_stdout {
+        let from = args
+            .events_from
+            .as_ref()
+            .map(|spec| spec.resolve(archive.headers.as_ref(), magic))
+            .transpose()
+            .unwrap_or_else(|e| {
+                args::Args::command()
+                    .error(ErrorKind::ValueValidation, format!("invalid value for '--events-from': {}", e))
+                    .exit()
+            });
+        events::stream_stdout(events.clone(), archive.clone(), index.clone(), from);
     }
 
-    for connection in connections.iter_mut() {
-        connection.join().expect("error while slurping");
+    if let Some(address) = &args.events_websocket {
+        EventServer::new(events.clone(), archive.clone(), index.clone(), magic)
+            .listen(address)
+            .unwrap_or_else(|e| args::Args::command().error(ErrorKind::Io, format!("{:#}", e)).exit());
     }
+
+    let peers = PeerStatsMap::default();
+    if let Some(address) = &args.http {
+        HttpServer::new(args.directory.clone(), archive.clone(), index.clone(), magic, peers.clone())
+            .serve(address)
+            .unwrap_or_else(|e| args::Args::command().error(ErrorKind::Io, format!("{:#}", e)).exit());
+    }
+
+    if let Some(address) = &args.serve {
+        Server::new(archive.clone(), index, magic)
+            .listen(address)
+            .unwrap_or_else(|e| args::Args::command().error(ErrorKind::Io, format!("{:#}", e)).exit());
+    }
+
+    if let Some(socket) = args.socket {
+        let mut local = LocalSlurp::new(args.directory.clone(), archive, socket, fallback_point, args.testnet_magic, frontier_mutex, events);
+        // Like a relay, the node may restart under us, so keep reconnecting and pick up from our cursor
+        loop {
+            match local.slurp() {
+                Ok(()) => local.join().expect("error while slurping"),
+                Err(e) => log::warn!(target: local_slurp::LOCAL_NAME, "unable to slurp from the node: {:#}", e),
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    }
+
+    let mut pool = Pool::new(
+        args.directory.clone(),
+        archive,
+        fallback_point,
+        args.testnet_magic,
+        frontier_mutex,
+        events,
+        webhooks,
+        Box::new(SystemResolver),
+        args.max_peers,
+        Duration::from_secs(args.keepalive_seconds),
+        peers,
+    );
+
+    let relays = || RelayGroup { relays: args.relay.clone(), ..Default::default() };
+    pool.set_relays(vec![relays()]);
+
+    // The topology file is re-read whenever it changes, but if it's broken to begin with, fail early
+    let mut watcher = args.topology_file.map(|topology_file| {
+        Topology::load(&topology_file).expect("unable to parse topology file");
+        TopologyWatcher::new(topology_file)
+    });
+    let mut last_tick: Option<Instant> = None;
+    loop {
+        let mut changed = false;
+        if let Some(topology) = watcher.as_mut().and_then(|w| w.poll()) {
+            if let Some(slot) = topology.use_ledger_after_slot.filter(|slot| *slot >= 0) {
+                log::warn!("ignoring useLedgerAfterSlot {} in the topology file; only the relays it lists are used", slot);
+            }
+            let mut groups = vec![relays()];
+            groups.extend(topology.relays());
+            pool.set_relays(groups);
+            changed = true;
+        }
+
+        if changed || last_tick.is_none_or(|t| t.elapsed() >= RECONNECT_INTERVAL) {
+            pool.tick();
+            last_tick = Some(Instant::now());
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// The network an ImmutableDB is for, whose epoch length decides how many slots each chunk covers
+fn immutable_network(magic: u64) -> &'static Network {
+    Network::from_magic(magic).unwrap_or_else(|| {
+        args::Args::command()
+            .error(ErrorKind::ValueValidation, format!("chunk sizes aren't known for network magic {}", magic))
+            .exit()
+    })
 }
@@ -0,0 +1,214 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use pallas::{
+    ledger::traverse::{MultiEraBlock, MultiEraHeader},
+    network::miniprotocols::Point,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cursor,
+    network::{Network, NETWORKS},
+    storage::{Layout, Storage},
+    utils::{LARGE_BUCKET_SIZE, SMALL_BUCKET_SIZE},
+};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+/// The version of the on-disk format described by the manifest, bumped whenever it changes incompatibly
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// How the bodies in an archive are compressed; the details readers need are in the dictionaries directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompressionSummary {
+    pub algorithm: String,
+    pub level: i32,
+    /// The id of the dictionary new bodies from each era are compressed with
+    pub dictionaries: BTreeMap<String, u32>,
+}
+
+/// A block, by its slot and hash
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockReference {
+    pub slot: u64,
+    pub hash: String,
+}
+
+impl BlockReference {
+    fn point(&self) -> anyhow::Result<Point> {
+        Ok(Point::Specific(self.slot, hex::decode(&self.hash)?))
+    }
+}
+
+/// A description of an archive as a whole, kept in {directory}/manifest.json
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub layout_version: u32,
+    pub network: Option<String>,
+    pub network_magic: u64,
+    /// The hash of the network's byron genesis file, if it's one we know
+    pub genesis_hash: Option<String>,
+    /// The oldest block in the archive, recorded the first time we run against it with any blocks in it
+    #[serde(default)]
+    pub first_block: Option<BlockReference>,
+    pub layout: Layout,
+    pub large_bucket_size: u64,
+    pub small_bucket_size: u64,
+    pub compression: Option<CompressionSummary>,
+    /// Whether the index covers every transaction, as well as every block; see `--index-transactions`
+    #[serde(default)]
+    pub index_transactions: bool,
+    /// Whether each transaction is saved on its own as well, under txs; see `--save-transactions`
+    #[serde(default)]
+    pub save_transactions: bool,
+    pub created_at: u64,
+}
+
+impl Manifest {
+    pub fn new(magic: u64, layout: Layout, compression: Option<CompressionSummary>) -> anyhow::Result<Self> {
+        let network = Network::from_magic(magic);
+        Ok(Manifest {
+            layout_version: LAYOUT_VERSION,
+            network: network.map(|n| n.name.to_string()),
+            network_magic: magic,
+            genesis_hash: network.map(|n| n.genesis_hash.to_string()),
+            first_block: None,
+            layout,
+            large_bucket_size: LARGE_BUCKET_SIZE,
+            small_bucket_size: SMALL_BUCKET_SIZE,
+            compression,
+            index_transactions: false,
+            save_transactions: false,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        })
+    }
+
+    /// Read the manifest of an archive, if it has one yet
+    pub fn load(directory: &Path) -> anyhow::Result<Option<Manifest>> {
+        let path = directory.join(MANIFEST_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = fs::File::open(&path)?;
+        let manifest = serde_json::from_reader(file).with_context(|| format!("unable to parse {:?}", path))?;
+        Ok(Some(manifest))
+    }
+
+    pub fn save(&self, directory: &Path) -> anyhow::Result<()> {
+        fs::write(directory.join(MANIFEST_NAME), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Make sure we can safely read and write an archive with this manifest, for the given network
+    ///
+    /// Besides what the manifest says, the blocks themselves are checked: the archive has to still hold the block
+    /// it started with, and its oldest block has to be from the network we were asked for.
+    pub fn check(&self, magic: u64, bodies: &dyn Storage) -> anyhow::Result<()> {
+        if self.network_magic != magic {
+            bail!("the archive is for {}, but {} was asked for", describe(self.network_magic), describe(magic));
+        }
+        if let Some(first_block) = &self.first_block {
+            if !bodies.contains(&first_block.point()?) {
+                bail!(
+                    "the archive no longer has the block it started with, {}/{}, so it isn't the archive its manifest describes",
+                    first_block.slot,
+                    first_block.hash
+                );
+            }
+        }
+        if let Some(found) = stored_magic(bodies)? {
+            if found != magic {
+                bail!("the archive's blocks are from {}, but {} was asked for", describe(found), describe(magic));
+            }
+        }
+        if let (Some(recorded), Some(found)) = (&self.genesis_hash, stored_genesis_hash(bodies)?) {
+            if *recorded != found {
+                bail!("the archive's first block follows genesis {}, but its manifest records genesis {}", found, recorded);
+            }
+        }
+        if self.layout_version > LAYOUT_VERSION {
+            bail!("the archive is in version {} of the format, which is newer than we understand", self.layout_version);
+        }
+        if self.large_bucket_size != LARGE_BUCKET_SIZE || self.small_bucket_size != SMALL_BUCKET_SIZE {
+            bail!(
+                "the archive is bucketed every {} and {} slots, but we bucket every {} and {}",
+                self.large_bucket_size,
+                self.small_bucket_size,
+                LARGE_BUCKET_SIZE,
+                SMALL_BUCKET_SIZE
+            );
+        }
+        Ok(())
+    }
+
+    /// Make sure an archive from before there were manifests is for the network we were asked for, going by
+    /// the network its cursors were recorded on, or failing that, its blocks
+    pub fn check_unrecorded(directory: &Path, magic: u64, bodies: &dyn Storage) -> anyhow::Result<()> {
+        if let Some(found) = cursor::recorded_magic(directory) {
+            if found != magic {
+                bail!("the archive's cursors were recorded on {}, but {} was asked for", describe(found), describe(magic));
+            }
+        }
+        if let Some(found) = stored_magic(bodies)? {
+            if found != magic {
+                bail!("the archive's blocks are from {}, but {} was asked for", describe(found), describe(magic));
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the oldest block in the archive, if we haven't already and there is one, returning whether we did
+    pub fn record_first_block(&mut self, bodies: &dyn Storage) -> bool {
+        if self.first_block.is_some() {
+            return false;
+        }
+        let Some(Point::Specific(slot, hash)) = bodies.points().next() else { return false };
+        self.first_block = Some(BlockReference { slot, hash: hex::encode(hash) });
+        true
+    }
+}
+
+fn describe(magic: u64) -> String {
+    match Network::from_magic(magic) {
+        Some(network) => format!("{} (network magic {})", network.name, magic),
+        None => format!("network magic {}", magic),
+    }
+}
+
+/// The oldest block in the archive, if there is one
+fn oldest_block(bodies: &dyn Storage) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(point) = bodies.points().next() else { return Ok(None) };
+    bodies.read(&point)
+}
+
+/// The network magic the blocks in an archive were made on, if they tell us
+///
+/// Byron blocks carry the protocol magic in their headers; later blocks don't, so for those we look for the
+/// blocks at the era boundaries of each of the public networks.
+fn stored_magic(bodies: &dyn Storage) -> anyhow::Result<Option<u64>> {
+    let Some(body) = oldest_block(bodies)? else { return Ok(None) };
+    match MultiEraBlock::decode(&body)?.header() {
+        MultiEraHeader::EpochBoundary(x) => return Ok(Some(x.protocol_magic.into())),
+        MultiEraHeader::Byron(x) => return Ok(Some(x.protocol_magic.into())),
+        _ => {}
+    }
+    Ok(NETWORKS
+        .iter()
+        .find(|network| network.boundaries.iter().any(|b| bodies.contains(&network.era_start(b.era).unwrap())))
+        .map(|network| network.magic))
+}
+
+/// The hash of the genesis file the archive's chain starts from, if it holds the very first block
+fn stored_genesis_hash(bodies: &dyn Storage) -> anyhow::Result<Option<String>> {
+    let Some(body) = oldest_block(bodies)? else { return Ok(None) };
+    Ok(match MultiEraBlock::decode(&body)?.header() {
+        // The first epoch boundary block follows on from the genesis file itself
+        MultiEraHeader::EpochBoundary(x) if x.consensus_data.epoch_id == 0 => Some(hex::encode(x.prev_block)),
+        _ => None,
+    })
+}
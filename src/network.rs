@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use pallas::network::miniprotocols::{Point, MAINNET_MAGIC, PREVIEW_MAGIC, PRE_PRODUCTION_MAGIC};
+
+/// The slot and hash of the last block before an era begins, which is the point to intersect at
+/// in order to start syncing from the beginning of that era
+pub struct EraBoundary {
+    pub era: &'static str,
+    pub slot: u64,
+    pub hash: &'static str,
+}
+
+/// How often a block is made, on average: on every public network, slots are a second long, and one in twenty
+/// has a block
+pub const EXPECTED_BLOCK_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Well known parameters of the public cardano networks
+pub struct Network {
+    pub name: &'static str,
+    pub magic: u64,
+    /// The hash of the byron genesis file, which identifies the network along with its magic
+    pub genesis_hash: &'static str,
+    pub byron_epoch_length: u64,
+    /// How many blocks deep a block must be before it can no longer be rolled back
+    pub security_parameter: u64,
+    pub shelley_start_epoch: u64,
+    pub shelley_epoch_length: u64,
+    pub boundaries: &'static [EraBoundary],
+}
+
+pub const NETWORKS: &[Network] = &[
+    Network {
+        name: "mainnet",
+        magic: MAINNET_MAGIC,
+        genesis_hash: "5f20df933584822601f9e3f8c024eb5eb252fe8cefb24d1317dc3d432e940ebb",
+        byron_epoch_length: 21600,
+        security_parameter: 2160,
+        shelley_start_epoch: 208,
+        shelley_epoch_length: 432000,
+        boundaries: &[
+            EraBoundary { era: "shelley", slot: 4492799, hash: "f8084c61b6a238acec985b59310b6ecec49c0ab8352249afd7268da5cff2a457" },
+            EraBoundary { era: "allegra", slot: 16588737, hash: "4e9bbbb67e3ae262133d94c3da5bffce7b1127fc436e7433b87668dba34c354a" },
+            EraBoundary { era: "mary", slot: 23068793, hash: "69c44ac1dda2ec74646e4223bc804d9126f719b1c245dadc2ad65e8de1b276d7" },
+            EraBoundary { era: "alonzo", slot: 39916796, hash: "e72579ff89dc9ed325b723a33624b596c08141c7bd573ecfff56a1f7229e4d09" },
+            EraBoundary { era: "babbage", slot: 72316796, hash: "c58a24ba8203e7629422a24d9dc68ce2ed495420bf40d9dab124373655161a20" },
+        ],
+    },
+    Network {
+        name: "preprod",
+        magic: PRE_PRODUCTION_MAGIC,
+        genesis_hash: "d4b8de7a11d929a323373cbab6c1a9bdc931beffff11db111cf9d57356ee1937",
+        byron_epoch_length: 21600,
+        security_parameter: 2160,
+        shelley_start_epoch: 4,
+        shelley_epoch_length: 432000,
+        boundaries: &[
+            EraBoundary { era: "shelley", slot: 84916, hash: "732bfd67e66be8e8288349fcaaa2294973ef6271cc189a239bb431275401b8e5" },
+            EraBoundary { era: "allegra", slot: 518360, hash: "f9d8b6c77fedd60c3caf5de0ce63a0aeb9d1753269c9c07503d9aa09d5144481" },
+            EraBoundary { era: "mary", slot: 950340, hash: "74c03af754bcde9cd242c5a168689edcab1756a3f7ae4d5dca1a31d86839c7b1" },
+            EraBoundary { era: "alonzo", slot: 1382370, hash: "af5fddc7d16a349e1a2af8ba89f4f5d3273955a13095b3709ef6e3db576a0b33" },
+            EraBoundary { era: "babbage", slot: 3542390, hash: "f93e682d5b91a94d8660e748aef229c19cb285bfb9830db48941d6a78183d81f" },
+        ],
+    },
+    Network {
+        name: "preview",
+        magic: PREVIEW_MAGIC,
+        genesis_hash: "83de1d7302569ad56cf9139a41e2e11346d4cb4a31c00142557b6ab3fa550761",
+        byron_epoch_length: 4320,
+        security_parameter: 432,
+        shelley_start_epoch: 0,
+        shelley_epoch_length: 86400,
+        boundaries: &[
+            EraBoundary { era: "babbage", slot: 259180, hash: "0ad91d3bbe350b1cfa05b13dba5263c47c5eca4f97b3a3105eba96416785a487" },
+        ],
+    },
+];
+
+impl Network {
+    pub fn from_magic(magic: u64) -> Option<&'static Network> {
+        NETWORKS.iter().find(|n| n.magic == magic)
+    }
+
+    /// The first slot of the given epoch, accounting for the change in epoch length at the start of shelley
+    pub fn epoch_start_slot(&self, epoch: u64) -> u64 {
+        if epoch < self.shelley_start_epoch {
+            epoch * self.byron_epoch_length
+        } else {
+            self.shelley_start_epoch * self.byron_epoch_length
+                + (epoch - self.shelley_start_epoch) * self.shelley_epoch_length
+        }
+    }
+
+    /// The epoch a slot falls in; the inverse of [Network::epoch_start_slot]
+    pub fn slot_epoch(&self, slot: u64) -> u64 {
+        let shelley_start_slot = self.shelley_start_epoch * self.byron_epoch_length;
+        if slot < shelley_start_slot {
+            slot / self.byron_epoch_length
+        } else {
+            self.shelley_start_epoch + (slot - shelley_start_slot) / self.shelley_epoch_length
+        }
+    }
+
+    /// The point to intersect at in order to start syncing from the beginning of the named era
+    pub fn era_start(&self, era: &str) -> Option<Point> {
+        if era == "byron" {
+            return Some(Point::Origin);
+        }
+        let boundary = self.boundaries.iter().find(|b| b.era == era)?;
+        let hash = hex::decode(boundary.hash).expect("invalid era boundary hash");
+        Some(Point::Specific(boundary.slot, hash))
+    }
+}
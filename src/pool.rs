@@ -0,0 +1,559 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use pallas::network::miniprotocols::Point;
+
+use crate::{
+    cursor::Cursor,
+    events::Events,
+    resolver::Resolver,
+    slurp::Slurp,
+    stats::{PeerStats, PeerStatsMap},
+    storage::Archive,
+    webhooks::{Notification, Webhooks},
+};
+
+/// How long we give a peer we're no longer interested in to finish downloading blocks, before we cut it off
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often we consider swapping the worst of our active peers for a better candidate, when limited to a number of peers
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Relays given together, on the command line or as a group in a topology file, and how many of the peers they
+/// resolve to between them we should use at once
+#[derive(Default)]
+pub struct RelayGroup {
+    pub relays: Vec<String>,
+    /// How many peers to connect to, if not all of them
+    pub hot_valency: Option<usize>,
+    /// How many peers to consider, at least as many as the hot valency, when we're choosing peers for ourselves
+    /// with `--max-peers`
+    pub warm_valency: Option<usize>,
+}
+
+impl RelayGroup {
+    /// The most peers from the group to connect to at once, if there's a limit
+    fn limit(&self, choosing: bool) -> Option<usize> {
+        let hot = self.hot_valency?;
+        Some(if choosing { self.warm_valency.unwrap_or(hot).max(hot) } else { hot })
+    }
+}
+
+/// The set of relays we're slurping from, and the live connections to the peers behind each one
+pub struct Pool {
+    directory: PathBuf,
+    archive: Archive,
+    fallback_point: Option<Point>,
+    magic: Option<u64>,
+    frontier_mutex: Arc<Mutex<Cursor>>,
+    events: Arc<Events>,
+    webhooks: Arc<Webhooks>,
+    resolver: Box<dyn Resolver>,
+    /// The most peers to slurp from at once, if any
+    max_peers: Option<usize>,
+    /// How often to send each peer a keep-alive
+    keepalive_interval: Duration,
+
+    /// Each relay we were asked to connect to, and the peers it last resolved to
+    relays: BTreeMap<String, Vec<SocketAddr>>,
+    /// The groups the relays were given in, which limit how many of their peers we use
+    groups: Vec<RelayGroup>,
+    /// Relays that need resolving before we next connect to them
+    unresolved: BTreeSet<String>,
+    connections: HashMap<SocketAddr, Slurp>,
+    /// Connections we're winding down, and when we started doing so
+    draining: Vec<(Instant, Slurp)>,
+    /// Connections we've closed, and are waiting on the threads of, rather than holding up the pool
+    closing: Vec<Slurp>,
+    /// Everything we know about every peer we've tried, which outlives any single connection
+    stats: PeerStatsMap,
+    /// Connected peers we've reported as stalled, and haven't given us a block since
+    stalled: HashSet<SocketAddr>,
+    last_evaluation: Instant,
+}
+
+impl Pool {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        directory: PathBuf,
+        archive: Archive,
+        fallback_point: Option<Point>,
+        magic: Option<u64>,
+        frontier_mutex: Arc<Mutex<Cursor>>,
+        events: Arc<Events>,
+        webhooks: Arc<Webhooks>,
+        resolver: Box<dyn Resolver>,
+        max_peers: Option<usize>,
+        keepalive_interval: Duration,
+        stats: PeerStatsMap,
+    ) -> Self {
+        Self {
+            directory,
+            archive,
+            fallback_point,
+            magic,
+            frontier_mutex,
+            events,
+            webhooks,
+            resolver,
+            max_peers,
+            keepalive_interval,
+            relays: BTreeMap::new(),
+            groups: vec![],
+            unresolved: BTreeSet::new(),
+            connections: HashMap::new(),
+            draining: vec![],
+            closing: vec![],
+            stats,
+            stalled: HashSet::new(),
+            last_evaluation: Instant::now(),
+        }
+    }
+
+    pub fn add_relay(&mut self, relay: String) {
+        self.relays.entry(relay.clone()).or_default();
+        self.unresolved.insert(relay);
+    }
+
+    /// Replace the set of relays we're slurping from, draining the peers of any relays that have been removed
+    pub fn set_relays(&mut self, groups: Vec<RelayGroup>) {
+        let relays: Vec<String> = groups.iter().flat_map(|g| g.relays.iter().cloned()).collect();
+        self.groups = groups;
+        let removed: Vec<String> = self.relays.keys().filter(|r| !relays.contains(r)).cloned().collect();
+        for relay in removed {
+            log::info!("no longer slurping from {}", relay);
+            let peers = self.relays.remove(&relay).unwrap();
+            self.unresolved.remove(&relay);
+            for address in peers {
+                self.retire(&relay, address);
+            }
+        }
+        for relay in relays {
+            if !self.relays.contains_key(&relay) {
+                log::info!("now slurping from {}", relay);
+                self.add_relay(relay);
+            }
+        }
+    }
+
+    /// Gracefully drain the connection to a peer that a relay no longer points to, unless another relay still does
+    fn retire(&mut self, relay: &str, address: SocketAddr) {
+        let still_wanted = self
+            .relays
+            .iter()
+            .any(|(other, peers)| other != relay && peers.contains(&address));
+        if still_wanted {
+            return;
+        }
+        self.drain(address);
+    }
+
+    fn drain(&mut self, address: SocketAddr) {
+        if let Some(mut connection) = self.connections.remove(&address) {
+            connection.drain();
+            self.draining.push((Instant::now(), connection));
+        }
+    }
+
+    /// Clean up any connections that have dropped, and (re)connect to the peers we want to be connected to
+    pub fn tick(&mut self) {
+        self.watchdog();
+        self.reap();
+        self.watch_stalls();
+        let unresolved: Vec<String> = std::mem::take(&mut self.unresolved).into_iter().collect();
+        for relay in unresolved {
+            self.resolve_relay(&relay);
+        }
+        self.evaluate();
+        self.fill();
+    }
+
+    fn reap(&mut self) {
+        for (since, connection) in std::mem::take(&mut self.draining) {
+            if connection.is_drained() {
+                log::info!("finished draining {}", connection.address);
+                self.close(connection);
+            } else if since.elapsed() > DRAIN_TIMEOUT {
+                log::warn!("timed out draining {}", connection.address);
+                self.close(connection);
+            } else {
+                self.draining.push((since, connection));
+            }
+        }
+        self.closing.retain_mut(|connection| {
+            if !connection.is_drained() {
+                return true;
+            }
+            if connection.join().is_err() {
+                log::warn!("slurp thread for {} panicked", connection.address);
+            }
+            false
+        });
+
+        let finished: Vec<SocketAddr> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.is_finished())
+            .map(|(address, _)| *address)
+            .collect();
+        for address in finished {
+            log::warn!("lost connection to {}", address);
+            let connection = self.connections.remove(&address).unwrap();
+            let (point, tip) = connection.position();
+            self.webhooks.notify(Notification::Disconnect, &connection.relay, point.as_ref(), tip.as_ref());
+            self.stalled.remove(&address);
+            self.close(connection);
+            self.mark_unresolved(address);
+        }
+    }
+
+    /// Close a connection, and leave its threads to wind down in the background, as one may be stuck for a while
+    /// waiting on something else, such as an event sink; [Pool::reap] joins them once they have
+    fn close(&mut self, mut connection: Slurp) {
+        connection.close();
+        self.closing.push(connection);
+    }
+
+    /// Tear down connections that have gone quiet for longer than we'd expect, such as when a relay stops responding
+    /// without closing the connection, so that they're reconnected
+    fn watchdog(&mut self) {
+        let stuck: Vec<(SocketAddr, Duration)> = self
+            .connections
+            .keys()
+            .filter_map(|address| {
+                let stuck_for = self.stats(*address).lock().expect("unable to acquire lock").stuck_for()?;
+                Some((*address, stuck_for))
+            })
+            .collect();
+        for (address, stuck_for) in stuck {
+            log::warn!("{} has been quiet for {:?}, reconnecting", address, stuck_for);
+            self.stats(address).lock().expect("unable to acquire lock").stalls += 1;
+            let connection = self.connections.remove(&address).unwrap();
+            self.close(connection);
+            self.stalled.remove(&address);
+            self.mark_unresolved(address);
+        }
+    }
+
+    /// Report peers we're connected to that haven't given us a block in a while, once each time they stall
+    fn watch_stalls(&mut self) {
+        let Some(stall_interval) = self.webhooks.stall_interval else { return };
+        for (address, connection) in &self.connections {
+            let since = self.stats(*address).lock().expect("unable to acquire lock").since_last_block();
+            match since {
+                Some(since) if since > stall_interval => {
+                    if self.stalled.insert(*address) {
+                        log::warn!("no new blocks from {} in {:?}", address, since);
+                        let (point, tip) = connection.position();
+                        self.webhooks.notify(Notification::Stall { since }, &connection.relay, point.as_ref(), tip.as_ref());
+                    }
+                }
+                _ => {
+                    self.stalled.remove(address);
+                }
+            }
+        }
+    }
+
+    /// Make sure the relays behind a peer are resolved again before we reconnect to it, in case their addresses have changed
+    fn mark_unresolved(&mut self, address: SocketAddr) {
+        for (relay, peers) in self.relays.iter() {
+            if peers.contains(&address) {
+                self.unresolved.insert(relay.clone());
+            }
+        }
+    }
+
+    fn resolve_relay(&mut self, relay: &str) {
+        let resolved = match self.resolver.resolve(relay) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                log::warn!("unable to resolve {}: {}", relay, e);
+                self.unresolved.insert(relay.to_string());
+                return;
+            }
+        };
+        if resolved.is_empty() {
+            log::warn!("{} didn't resolve to any addresses", relay);
+        }
+
+        let stale: Vec<SocketAddr> = self.relays[relay].iter().filter(|p| !resolved.contains(p)).cloned().collect();
+        for address in stale {
+            log::info!("{} no longer resolves to {}", relay, address);
+            self.retire(relay, address);
+        }
+        self.relays.insert(relay.to_string(), resolved);
+    }
+
+    fn stats(&self, address: SocketAddr) -> Arc<Mutex<PeerStats>> {
+        self.stats.lock().expect("unable to acquire lock").entry(address).or_default().clone()
+    }
+
+    fn score(&self, address: &SocketAddr) -> Option<f64> {
+        let stats = self.stats.lock().expect("unable to acquire lock").get(address).cloned();
+        stats.and_then(|s| s.lock().expect("unable to acquire lock").score())
+    }
+
+    /// Every peer behind any relay that we aren't currently connected to, or still winding down a connection to,
+    /// and that its group has room for, best first
+    ///
+    /// Peers we haven't tried yet come before any we have, so that every candidate gets a chance
+    fn candidates(&self) -> Vec<SocketAddr> {
+        let mut candidates: Vec<SocketAddr> = self
+            .relays
+            .values()
+            .flatten()
+            .filter(|a| !self.connections.contains_key(a))
+            .filter(|a| !self.draining.iter().any(|(_, connection)| connection.address == **a))
+            .filter(|a| !self.closing.iter().any(|connection| connection.address == **a))
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        candidates.sort_by(|a, b| {
+            let (a, b) = (self.score(a).unwrap_or(f64::INFINITY), self.score(b).unwrap_or(f64::INFINITY));
+            b.total_cmp(&a)
+        });
+
+        // Valency counts peers, not relays, so a relay that resolves to several addresses only gets its share
+        let mut room: Vec<Option<usize>> = self
+            .groups
+            .iter()
+            .map(|group| {
+                let limit = group.limit(self.max_peers.is_some())?;
+                let peers = self.group_peers(group);
+                Some(limit.saturating_sub(self.connections.keys().filter(|a| peers.contains(a)).count()))
+            })
+            .collect();
+        candidates.retain(|address| {
+            let groups: Vec<usize> = (0..self.groups.len()).filter(|&g| self.group_peers(&self.groups[g]).contains(address)).collect();
+            // Peers of relays outside any group, or in a group without a limit, are always welcome
+            if groups.is_empty() || groups.iter().any(|&g| room[g].is_none()) {
+                return true;
+            }
+            let Some(&g) = groups.iter().find(|&&g| room[g] > Some(0)) else { return false };
+            room[g] = room[g].map(|r| r - 1);
+            true
+        });
+        candidates
+    }
+
+    /// Every peer the relays in a group resolved to
+    fn group_peers(&self, group: &RelayGroup) -> BTreeSet<SocketAddr> {
+        group.relays.iter().filter_map(|relay| self.relays.get(relay)).flatten().cloned().collect()
+    }
+
+    /// When we're limited in the number of peers we can use, periodically cycle out the worst of them if there's a better candidate
+    fn evaluate(&mut self) {
+        let Some(max_peers) = self.max_peers else { return };
+        if self.last_evaluation.elapsed() < EVALUATION_INTERVAL || self.connections.len() < max_peers {
+            return;
+        }
+        self.last_evaluation = Instant::now();
+
+        for address in self.connections.keys() {
+            let stats = self.stats(*address);
+            let stats = stats.lock().expect("unable to acquire lock");
+            log::info!(
+                "{} has scored {:.3} ({} blocks at {:.2} blocks/s, {} rollbacks, latency {:?}, keep-alive {:?})",
+                address,
+                stats.score().unwrap_or_default(),
+                stats.blocks,
+                stats.blocks_per_second(),
+                stats.rollbacks,
+                stats.latency.unwrap_or_default(),
+                stats.keepalive_rtt,
+            );
+        }
+
+        let worst = self
+            .connections
+            .keys()
+            .map(|a| (*a, self.score(a).unwrap_or_default()))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let best = self
+            .candidates()
+            .first()
+            .map(|a| (*a, self.score(a).unwrap_or(f64::INFINITY)));
+        if let (Some((worst, worst_score)), Some((best, best_score))) = (worst, best) {
+            if best_score > worst_score {
+                log::info!("swapping out {} (score {:.3}) to try {}", worst, worst_score, best);
+                self.drain(worst);
+            }
+        }
+    }
+
+    /// Connect to the best candidates, until we've got as many peers as we're allowed
+    fn fill(&mut self) {
+        for address in self.candidates() {
+            if self.max_peers.is_some_and(|max| self.connections.len() >= max) {
+                break;
+            }
+            self.connect(address);
+        }
+    }
+
+    fn connect(&mut self, address: SocketAddr) {
+        let stats = self.stats(address);
+        let mut slurp = Slurp::new(
+            self.directory.clone(),
+            self.archive.clone(),
+            address,
+            self.fallback_point.clone(),
+            self.magic,
+            self.frontier_mutex.clone(),
+            self.events.clone(),
+            self.webhooks.clone(),
+            self.keepalive_interval,
+            stats,
+        );
+        match slurp.slurp() {
+            Ok(()) => {
+                self.connections.insert(address, slurp);
+            }
+            Err(e) => {
+                log::warn!("unable to slurp from {}: {}", address, e);
+                slurp.disconnect();
+                self.mark_unresolved(address);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, net::TcpListener};
+
+    use pallas::network::miniprotocols::MAINNET_MAGIC;
+
+    use super::*;
+    use crate::{cursor::FRONTIER_NAME, storage::Layout, testing::TempDir};
+
+    /// Gives each answer in turn, sticking with the last, and records which relays it was asked about
+    struct ScriptedResolver {
+        answers: Mutex<VecDeque<Vec<SocketAddr>>>,
+        asked: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Resolver for ScriptedResolver {
+        fn resolve(&self, relay: &str) -> anyhow::Result<Vec<SocketAddr>> {
+            self.asked.lock().unwrap().push(relay.to_string());
+            let mut answers = self.answers.lock().unwrap();
+            Ok(if answers.len() > 1 { answers.pop_front().unwrap() } else { answers[0].clone() })
+        }
+    }
+
+    /// Addresses nothing is listening on, so that connecting to them fails straight away
+    fn closed_addresses(count: usize) -> Vec<SocketAddr> {
+        let listeners: Vec<TcpListener> = (0..count).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        listeners.iter().map(|l| l.local_addr().unwrap()).collect()
+    }
+
+    fn pool(directory: &TempDir, resolver: ScriptedResolver) -> Pool {
+        let frontier = Cursor::new(FRONTIER_NAME.to_string(), MAINNET_MAGIC, VecDeque::new());
+        Pool::new(
+            directory.path().to_path_buf(),
+            Archive::open_raw(directory.path(), Layout::Files).unwrap(),
+            None,
+            None,
+            Arc::new(Mutex::new(frontier)),
+            Arc::new(Events::default()),
+            Arc::new(Webhooks::default()),
+            Box::new(resolver),
+            None,
+            Duration::from_secs(30),
+            PeerStatsMap::default(),
+        )
+    }
+
+    fn failures(pool: &Pool, address: SocketAddr) -> u32 {
+        pool.stats(address).lock().unwrap().failed_handshakes
+    }
+
+    #[test]
+    fn peers_are_not_reconnected_until_the_old_connection_has_closed() {
+        let directory = TempDir::new("pool");
+        let address = closed_addresses(1)[0];
+        let resolver = ScriptedResolver { answers: Mutex::new(VecDeque::from([vec![address]])), asked: Arc::default() };
+        let mut pool = pool(&directory, resolver);
+        // A connection whose threads never finish winding down, as if its body slurp were stuck on an event sink
+        let frontier = Arc::new(Mutex::new(Cursor::new(FRONTIER_NAME.to_string(), MAINNET_MAGIC, VecDeque::new())));
+        let stuck = Slurp::new(
+            directory.path().to_path_buf(),
+            Archive::open_raw(directory.path(), Layout::Files).unwrap(),
+            address,
+            None,
+            None,
+            frontier,
+            Arc::new(Events::default()),
+            Arc::new(Webhooks::default()),
+            Duration::from_secs(30),
+            pool.stats(address),
+        );
+        pool.closing.push(stuck);
+        pool.add_relay("relay.example:3001".to_string());
+
+        pool.tick();
+        pool.tick();
+        assert_eq!(failures(&pool, address), 0);
+
+        pool.closing.clear();
+        pool.tick();
+        assert_eq!(failures(&pool, address), 1);
+    }
+
+    #[test]
+    fn valency_limits_the_peers_behind_a_group_of_relays() {
+        let directory = TempDir::new("pool");
+        let addresses = closed_addresses(4);
+        let resolver = ScriptedResolver { answers: Mutex::new(VecDeque::from([addresses.clone()])), asked: Arc::default() };
+        let mut pool = pool(&directory, resolver);
+        pool.set_relays(vec![RelayGroup {
+            relays: vec!["relay.example:3001".to_string()],
+            hot_valency: Some(1),
+            warm_valency: Some(3),
+        }]);
+
+        // One relay, four peers behind it, but only one of them is hot
+        pool.tick();
+        assert_eq!(addresses.iter().map(|a| failures(&pool, *a)).sum::<u32>(), 1);
+
+        // Choosing peers for ourselves, the warm ones are fair game too
+        pool.max_peers = Some(10);
+        pool.tick();
+        assert_eq!(addresses.iter().map(|a| failures(&pool, *a)).sum::<u32>(), 4);
+    }
+
+    #[test]
+    fn relays_are_resolved_again_before_reconnecting() {
+        let directory = TempDir::new("pool");
+        let addresses = closed_addresses(2);
+        let asked = Arc::new(Mutex::new(vec![]));
+        let resolver = ScriptedResolver {
+            answers: Mutex::new(VecDeque::from([vec![addresses[0]], vec![addresses[1]]])),
+            asked: asked.clone(),
+        };
+        let mut pool = pool(&directory, resolver);
+        pool.add_relay("relay.example:3001".to_string());
+
+        pool.tick();
+        assert_eq!(asked.lock().unwrap().len(), 1);
+        assert_eq!(pool.relays["relay.example:3001"], vec![addresses[0]]);
+        assert_eq!(failures(&pool, addresses[0]), 1);
+
+        // The relay has moved by the time we try again, so we follow it rather than retrying the old address
+        pool.tick();
+        assert_eq!(asked.lock().unwrap().len(), 2);
+        assert_eq!(pool.relays["relay.example:3001"], vec![addresses[1]]);
+        assert_eq!(failures(&pool, addresses[0]), 1);
+        assert_eq!(failures(&pool, addresses[1]), 1);
+
+        pool.tick();
+        assert_eq!(*asked.lock().unwrap(), vec!["relay.example:3001"; 3]);
+        assert_eq!(failures(&pool, addresses[1]), 2);
+    }
+}
@@ -0,0 +1,39 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Relays given with this prefix are looked up as DNS SRV records, rather than as a `host:port`
+pub const SRV_PREFIX: &str = "srv:";
+
+/// Expands a relay, as given on the command line or in a topology file, into the addresses of the
+/// individual peers behind it
+///
+/// This is a trait so that a stand-in can be used in place of real DNS
+pub trait Resolver {
+    fn resolve(&self, relay: &str) -> anyhow::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves relays using the system's DNS configuration
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, relay: &str) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut addresses: Vec<SocketAddr> = match relay.strip_prefix(SRV_PREFIX) {
+            Some(name) => resolve_srv(name)?,
+            None => relay.to_socket_addrs()?.collect(),
+        };
+        addresses.sort();
+        addresses.dedup();
+        Ok(addresses)
+    }
+}
+
+fn resolve_srv(name: &str) -> anyhow::Result<Vec<SocketAddr>> {
+    let resolver = hickory_resolver::Resolver::from_system_conf()?;
+    let mut addresses = vec![];
+    for record in resolver.srv_lookup(name)?.iter() {
+        let target = record.target().to_utf8();
+        for ip in resolver.lookup_ip(target.as_str())?.iter() {
+            addresses.push(SocketAddr::new(ip, record.port()));
+        }
+    }
+    Ok(addresses)
+}
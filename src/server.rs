@@ -0,0 +1,567 @@
+use std::{
+    collections::VecDeque,
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        mpsc::{Receiver, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use pallas::{
+    codec::minicbor::{data::Tag, decode, encode, Decode, Decoder, Encode, Encoder},
+    network::{
+        miniprotocols::{
+            blockfetch,
+            chainsync::{self, HeaderContent, SkippedContent, Tip},
+            handshake::{self, n2n::VersionData, RefuseReason},
+            Point,
+        },
+        multiplexer::{
+            agents::{Channel, ChannelBuffer, ChannelError},
+            bearers::Bearer,
+            Message, Payload, StdChannel, StdPlexer,
+        },
+    },
+};
+
+use crate::{index::Index, keepalive::{self, KEEPALIVE_CHANNEL}, storage::Archive, utils::extract_header};
+
+/// The node-to-node versions we can serve; later versions add fields to the handshake that we don't speak
+const SUPPORTED_VERSIONS: std::ops::RangeInclusive<u64> = 7..=10;
+/// How often to check for a new block, while a peer is waiting for one at the tip
+const TIP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Inbound segments arrive with the mode bit flipped, and replies must go out with it set, so this is
+/// also the hand-shake channel
+const RESPONDER: u16 = 0x8000;
+
+/// A handshake proposal from a peer: each version it speaks, and the network magic it proposed with it
+struct Proposal(Vec<(u64, Option<u64>)>);
+
+impl<'b> Decode<'b, ()> for Proposal {
+    fn decode(d: &mut Decoder<'b>, _ctx: &mut ()) -> Result<Self, decode::Error> {
+        d.array()?;
+        if d.u16()? != 0 {
+            return Err(decode::Error::message("expected a handshake proposal"));
+        }
+        let count = d.map()?.ok_or_else(|| decode::Error::message("expected a definite version table"))?;
+        let mut versions = vec![];
+        for _ in 0..count {
+            let version = d.u64()?;
+            // Every version so far starts its parameters with the network magic, but has its own idea of the rest
+            let start = d.position();
+            d.skip()?;
+            let mut parameters = Decoder::new(&d.input()[start..d.position()]);
+            let magic = parameters.array().and_then(|_| parameters.u64()).ok();
+            versions.push((version, magic));
+        }
+        Ok(Proposal(versions))
+    }
+}
+
+impl Encode<()> for Proposal {
+    fn encode<W: encode::Write>(&self, e: &mut Encoder<W>, _ctx: &mut ()) -> Result<(), encode::Error<W::Error>> {
+        e.array(2)?.u16(0)?.map(self.0.len() as u64)?;
+        for (version, magic) in &self.0 {
+            e.u64(*version)?.array(2)?.u64(magic.unwrap_or_default())?.bool(false)?;
+        }
+        Ok(())
+    }
+}
+
+/// A header as chainsync sends it: wrapped in the index of its era, and for byron, whether it's an
+/// epoch boundary block along with the size of the block
+struct ServedHeader(HeaderContent);
+
+impl Encode<()> for ServedHeader {
+    fn encode<W: encode::Write>(&self, e: &mut Encoder<W>, _ctx: &mut ()) -> Result<(), encode::Error<W::Error>> {
+        e.array(2)?.u8(self.0.variant)?;
+        if let Some((subtag, size)) = self.0.byron_prefix {
+            e.array(2)?.array(2)?.u8(subtag)?.u64(size)?;
+        }
+        e.tag(Tag::Cbor)?.bytes(&self.0.cbor)?;
+        Ok(())
+    }
+}
+
+impl<'b> Decode<'b, ()> for ServedHeader {
+    fn decode(d: &mut Decoder<'b>, ctx: &mut ()) -> Result<Self, decode::Error> {
+        Ok(ServedHeader(HeaderContent::decode(d, ctx)?))
+    }
+}
+
+/// A multiplexer channel that can tell whether the peer has gone, without waiting for it to send anything
+///
+/// While a peer waits at the tip for a new block, it's our turn to speak, so the peer has no reason to send
+/// anything, and reading from the channel would block until it did.
+#[derive(Clone)]
+struct WatchedChannel {
+    protocol: u16,
+    sender: Sender<Message>,
+    inbox: Arc<Mutex<Inbox>>,
+}
+
+struct Inbox {
+    receiver: Receiver<Payload>,
+    /// Chunks that arrived while we were checking on the peer, in order
+    early: VecDeque<Payload>,
+}
+
+impl WatchedChannel {
+    fn new((protocol, sender, receiver): StdChannel) -> Self {
+        let inbox = Inbox { receiver, early: VecDeque::new() };
+        Self { protocol, sender, inbox: Arc::new(Mutex::new(inbox)) }
+    }
+
+    /// Whether the connection has closed, keeping anything the peer sent in the meantime for the next read
+    fn is_closed(&self) -> bool {
+        let mut inbox = self.inbox.lock().expect("unable to acquire lock");
+        loop {
+            match inbox.receiver.try_recv() {
+                Ok(chunk) => inbox.early.push_back(chunk),
+                Err(TryRecvError::Empty) => return false,
+                // Only once we've taken everything it sent, so nothing is lost
+                Err(TryRecvError::Disconnected) => return inbox.early.is_empty(),
+            }
+        }
+    }
+}
+
+impl Channel for WatchedChannel {
+    fn enqueue_chunk(&mut self, chunk: Payload) -> Result<(), ChannelError> {
+        self.sender
+            .send((self.protocol, chunk))
+            .map_err(|e| ChannelError::NotConnected(Some(e.0 .1)))
+    }
+
+    fn dequeue_chunk(&mut self) -> Result<Payload, ChannelError> {
+        let mut inbox = self.inbox.lock().expect("unable to acquire lock");
+        if let Some(chunk) = inbox.early.pop_front() {
+            return Ok(chunk);
+        }
+        inbox.receiver.recv().map_err(|_| ChannelError::NotConnected(None))
+    }
+}
+
+/// What to tell a peer following the chain from some point
+enum Next {
+    Forward(Point),
+    Backward(Point),
+    Await,
+}
+
+/// Serves the archive to other nodes over the node-to-node protocols, so they can sync from us
+///
+/// The chain we serve is the longest one the index follows (see [Index]), so when a fork takes over,
+/// peers following us are rolled back to where the forks meet. Blocks are only served once we have their
+/// body.
+pub struct Server {
+    archive: Archive,
+    index: Arc<Index>,
+    magic: u64,
+}
+
+impl Server {
+    pub fn new(archive: Archive, index: Arc<Index>, magic: u64) -> Self {
+        Self { archive, index, magic }
+    }
+
+    /// Accept connections on `address` from now on, serving each peer on its own threads
+    pub fn listen(self, address: &str) -> anyhow::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(address).with_context(|| format!("unable to listen on {}", address))?;
+        log::info!("serving the archive to peers on {}", address);
+        let server = Arc::new(self);
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warn!("unable to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let server = server.clone();
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| "unknown peer".to_string());
+                    match server.serve_peer(&peer, stream) {
+                        Ok(()) => log::info!(target: &peer, "peer disconnected"),
+                        Err(e) => log::warn!(target: &peer, "stopped serving peer: {:#}", e),
+                    }
+                });
+            }
+        }))
+    }
+
+    fn serve_peer(self: Arc<Self>, peer: &str, stream: TcpStream) -> anyhow::Result<()> {
+        log::info!(target: peer, "peer connected");
+        stream.set_nodelay(true)?;
+        let mut plexer = StdPlexer::new(Bearer::Tcp(stream.try_clone()?));
+        let channel0 = plexer.use_channel(RESPONDER);
+        let channel2 = plexer.use_channel(RESPONDER | 2);
+        let channel3 = plexer.use_channel(RESPONDER | 3);
+        let channel8 = plexer.use_channel(RESPONDER | KEEPALIVE_CHANNEL);
+        plexer.muxer.spawn();
+        plexer.demuxer.spawn();
+
+        let version = self.handshake(channel0)?;
+        log::info!(target: peer, "hand-shake accepted, using version {}", version);
+
+        let chainsync = {
+            let server = self.clone();
+            let peer = peer.to_string();
+            thread::spawn(move || server.chainsync(&peer, channel2))
+        };
+        // Like the keep-alive client, this finishes on its own once the connection closes
+        thread::spawn(move || keepalive::respond(channel8));
+        let blockfetch = {
+            let server = self.clone();
+            let peer = peer.to_string();
+            thread::spawn(move || server.blockfetch(&peer, channel3))
+        };
+        let chainsync = chainsync.join().expect("chainsync server panicked");
+        // Once the peer stops following the chain, it has no more use for the connection
+        let _ = stream.shutdown(Shutdown::Both);
+        let blockfetch = blockfetch.join().expect("blockfetch server panicked");
+        chainsync.and(blockfetch)
+    }
+
+    fn handshake(&self, channel: StdChannel) -> anyhow::Result<u64> {
+        let mut buffer = ChannelBuffer::new(channel);
+        let Proposal(versions) = buffer.recv_full_msg()?;
+        let response = match versions.iter().filter(|(v, _)| SUPPORTED_VERSIONS.contains(v)).max() {
+            Some((version, Some(magic))) if *magic == self.magic => {
+                buffer.send_msg_chunks(&handshake::Message::Accept(*version, VersionData::new(self.magic, false)))?;
+                return Ok(*version);
+            }
+            Some((version, magic)) => RefuseReason::Refused(
+                *version,
+                format!("we serve network magic {}, not {}", self.magic, magic.unwrap_or_default()),
+            ),
+            None => RefuseReason::VersionMismatch(SUPPORTED_VERSIONS.collect()),
+        };
+        buffer.send_msg_chunks(&handshake::Message::<VersionData>::Refuse(response))?;
+        bail!("refused hand-shake, proposing versions {:?}", versions)
+    }
+
+    /// The tip of the chain we serve
+    fn tip(&self) -> anyhow::Result<Tip> {
+        Ok(match self.index.tip()? {
+            Some((point, height)) => Tip(point, height),
+            None => Tip(Point::Origin, 0),
+        })
+    }
+
+    /// Where a peer at `point` should go next
+    fn next(&self, point: &Point) -> anyhow::Result<Next> {
+        if let Some(next) = self.index.successor(point)? {
+            return Ok(Next::Forward(next));
+        }
+        if *point == Point::Origin || self.tip()?.0 == *point {
+            return Ok(Next::Await);
+        }
+        // We've switched forks since the peer got here, so take it back to where they meet
+        Ok(Next::Backward(self.index.intersection(point)?))
+    }
+
+    fn header(&self, point: &Point) -> anyhow::Result<ServedHeader> {
+        let Point::Specific(_, hash) = point else { bail!("origin has no header") };
+        let block = self.index.block(hash)?.context("block isn't in the index")?;
+        let cbor = match self.archive.headers.read(point)? {
+            Some(header) => header,
+            None => {
+                let body = self.archive.bodies.read(point)?.context("block isn't in the archive")?;
+                extract_header(&body).context("unable to find the block's header")?.to_vec()
+            }
+        };
+        let (variant, byron_prefix) = match block.era {
+            0 | 1 => (0, Some((block.era as u8, block.size))),
+            era => ((era - 1) as u8, None),
+        };
+        Ok(ServedHeader(HeaderContent { variant, byron_prefix, cbor }))
+    }
+
+    fn chainsync(&self, peer: &str, channel: StdChannel) -> anyhow::Result<()> {
+        let channel = WatchedChannel::new(channel);
+        let mut buffer = ChannelBuffer::new(channel.clone());
+        let mut point = Point::Origin;
+        // After an intersection, the node's first reply is always to roll back to it
+        let mut rollback = None;
+        loop {
+            match buffer.recv_full_msg::<chainsync::Message<SkippedContent>>()? {
+                chainsync::Message::FindIntersect(points) => {
+                    let mut found = None;
+                    for candidate in points {
+                        if self.index.is_on_chain(&candidate)? {
+                            found = Some(candidate);
+                            break;
+                        }
+                    }
+                    let tip = self.tip()?;
+                    match found {
+                        Some(found) => {
+                            log::info!(target: peer, "intersected at {:?}", found);
+                            point = found.clone();
+                            rollback = Some(found.clone());
+                            buffer.send_msg_chunks(&chainsync::Message::<ServedHeader>::IntersectFound(found, tip))?;
+                        }
+                        None => buffer.send_msg_chunks(&chainsync::Message::<ServedHeader>::IntersectNotFound(tip))?,
+                    }
+                }
+                chainsync::Message::RequestNext => {
+                    if let Some(rollback) = rollback.take() {
+                        buffer.send_msg_chunks(&chainsync::Message::<ServedHeader>::RollBackward(rollback, self.tip()?))?;
+                        continue;
+                    }
+                    let mut awaiting = false;
+                    loop {
+                        match self.next(&point)? {
+                            Next::Forward(next) => {
+                                let header = self.header(&next)?;
+                                buffer.send_msg_chunks(&chainsync::Message::RollForward(header, self.tip()?))?;
+                                point = next;
+                                break;
+                            }
+                            Next::Backward(to) => {
+                                log::info!(target: peer, "rolling back to {:?}", to);
+                                buffer.send_msg_chunks(&chainsync::Message::<ServedHeader>::RollBackward(to.clone(), self.tip()?))?;
+                                point = to;
+                                break;
+                            }
+                            Next::Await => {
+                                if !awaiting {
+                                    buffer.send_msg_chunks(&chainsync::Message::<ServedHeader>::AwaitReply)?;
+                                    awaiting = true;
+                                }
+                                if channel.is_closed() {
+                                    log::info!(target: peer, "peer went away while waiting at the tip");
+                                    return Ok(());
+                                }
+                                thread::sleep(TIP_POLL_INTERVAL);
+                            }
+                        }
+                    }
+                }
+                chainsync::Message::Done => return Ok(()),
+                _ => bail!("unexpected chainsync message"),
+            }
+        }
+    }
+
+    /// The points of the blocks from `from` to `to` inclusive, following `to` back through the blocks before it
+    fn range(&self, from: &Point, to: &Point) -> anyhow::Result<Option<Vec<Point>>> {
+        let (Point::Specific(from_slot, from_hash), Point::Specific(_, to_hash)) = (from, to) else { return Ok(None) };
+        let mut points = vec![];
+        let mut walker = to_hash.clone();
+        loop {
+            let Some(block) = self.index.block(&walker)? else { return Ok(None) };
+            if block.slot < *from_slot {
+                return Ok(None);
+            }
+            let done = walker == *from_hash;
+            points.push(Point::Specific(block.slot, walker));
+            if done {
+                points.reverse();
+                return Ok(Some(points));
+            }
+            let Some(previous) = block.previous else { return Ok(None) };
+            walker = previous;
+        }
+    }
+
+    fn blockfetch(&self, peer: &str, channel: StdChannel) -> anyhow::Result<()> {
+        let mut buffer = ChannelBuffer::new(channel);
+        loop {
+            match buffer.recv_full_msg::<blockfetch::Message>()? {
+                blockfetch::Message::RequestRange { range: (from, to) } => {
+                    let Some(points) = self.range(&from, &to)? else {
+                        log::info!(target: peer, "no blocks from {:?} to {:?}", from, to);
+                        buffer.send_msg_chunks(&blockfetch::Message::NoBlocks)?;
+                        continue;
+                    };
+                    buffer.send_msg_chunks(&blockfetch::Message::StartBatch)?;
+                    for point in &points {
+                        let body = self.archive.bodies.read(point)?.context("block isn't in the archive")?;
+                        buffer.send_msg_chunks(&blockfetch::Message::Block { body })?;
+                    }
+                    buffer.send_msg_chunks(&blockfetch::Message::BatchDone)?;
+                    log::info!(target: peer, "served {} blocks, up to {:?}", points.len(), to);
+                }
+                blockfetch::Message::ClientDone => return Ok(()),
+                _ => bail!("unexpected blockfetch message"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, net::TcpListener, sync::Mutex, time::Instant};
+
+    use pallas::network::miniprotocols::MAINNET_MAGIC;
+
+    use super::*;
+    use crate::{
+        cursor::{Cursor, FRONTIER_NAME},
+        events::Events,
+        slurp::Slurp,
+        storage::Layout,
+        testing::{babbage_block, webhook, TempDir},
+        webhooks::Webhooks,
+    };
+
+    /// A chain of `length` babbage blocks after `from` (or from the start), and the point of its tip
+    fn extend(archive: &Archive, from: Option<(u64, Vec<u8>)>, length: u64, issuer: u8) -> (u64, Vec<u8>) {
+        let (mut number, mut previous) = match from {
+            Some((number, hash)) => (number + 1, Some(hash)),
+            None => (0, None),
+        };
+        for _ in 0..length {
+            let (point, block) = babbage_block(number, number * 20 + issuer as u64, previous.as_deref(), issuer);
+            archive.bodies.write(&point, &block).unwrap();
+            let Point::Specific(_, hash) = point else { unreachable!() };
+            previous = Some(hash);
+            number += 1;
+        }
+        (number - 1, previous.unwrap())
+    }
+
+    fn point_of(archive: &Archive, hash: &[u8]) -> Option<Point> {
+        archive.bodies.points().find(|point| matches!(point, Point::Specific(_, h) if h == hash))
+    }
+
+    /// Wait for a slurp to have the block with the given hash, giving up after a while
+    fn wait_for(archive: &Archive, hash: &[u8]) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(30) {
+            if point_of(archive, hash).is_some() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn peers_sync_the_longest_chain_across_forks() {
+        let served = TempDir::new("server");
+        let index = Arc::new(Index::open(served.path(), MAINNET_MAGIC, false).unwrap());
+        let archive = Archive::open_raw(served.path(), Layout::Files).unwrap().indexed(index.clone());
+        let (_, second) = extend(&archive, None, 2, 0);
+        let (_, tip) = extend(&archive, Some((1, second.clone())), 4, 0);
+        // A block on a shorter fork, saved after the chain it competes with, as after a peer rolls back
+        let (_, orphan) = extend(&archive, Some((1, second.clone())), 1, 1);
+        assert_eq!(index.tip().unwrap().map(|(_, height)| height), Some(5));
+
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        Server::new(archive.clone(), index.clone(), MAINNET_MAGIC).listen(&address.to_string()).unwrap();
+
+        let following = TempDir::new("server-peer");
+        let synced = Archive::open_raw(following.path(), Layout::Files).unwrap();
+        let frontier = Cursor::new(FRONTIER_NAME.to_string(), MAINNET_MAGIC, VecDeque::new());
+        let mut slurp = Slurp::new(
+            following.path().to_path_buf(),
+            synced.clone(),
+            address,
+            None,
+            None,
+            Arc::new(Mutex::new(frontier)),
+            Arc::new(Events::default()),
+            Arc::new(Webhooks::default()),
+            Duration::from_secs(30),
+            Arc::new(Mutex::new(Default::default())),
+        );
+        slurp.slurp().unwrap();
+        assert!(wait_for(&synced, &tip), "the peer never reached the tip");
+        assert!(point_of(&synced, &orphan).is_none());
+
+        // Once the fork grows past the chain's tip, it takes over, and the peer is rolled back onto it
+        let (_, fork) = extend(&archive, Some((1, second)), 5, 1);
+        assert_eq!(index.tip().unwrap().map(|(point, _)| point), point_of(&archive, &fork));
+        assert!(wait_for(&synced, &fork), "the peer never switched forks");
+        assert!(point_of(&synced, &orphan).is_some());
+        assert!(!slurp.is_finished());
+        slurp.disconnect();
+    }
+
+    #[test]
+    fn reaching_the_tip_is_notified_each_time_we_catch_up() {
+        let served = TempDir::new("server");
+        let index = Arc::new(Index::open(served.path(), MAINNET_MAGIC, false).unwrap());
+        let archive = Archive::open_raw(served.path(), Layout::Files).unwrap().indexed(index.clone());
+        let (_, second) = extend(&archive, None, 2, 0);
+        let (_, tip) = extend(&archive, Some((1, second.clone())), 2, 0);
+
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        Server::new(archive.clone(), index, MAINNET_MAGIC).listen(&address.to_string()).unwrap();
+
+        let (url, received) = webhook(vec![]);
+        let following = TempDir::new("server-peer");
+        let synced = Archive::open_raw(following.path(), Layout::Files).unwrap();
+        let frontier = Cursor::new(FRONTIER_NAME.to_string(), MAINNET_MAGIC, VecDeque::new());
+        let mut slurp = Slurp::new(
+            following.path().to_path_buf(),
+            synced.clone(),
+            address,
+            None,
+            None,
+            Arc::new(Mutex::new(frontier)),
+            Arc::new(Events::default()),
+            Arc::new(Webhooks::new(vec![url], 100, Duration::from_secs(600))),
+            Duration::from_secs(30),
+            Arc::new(Mutex::new(Default::default())),
+        );
+        let next_tip = || loop {
+            let payload = received.recv_timeout(Duration::from_secs(30)).expect("no tip notification");
+            if payload["event"] == "tip" {
+                return payload["point"]["hash"].as_str().unwrap().to_string();
+            }
+        };
+        slurp.slurp().unwrap();
+        assert_eq!(next_tip(), hex::encode(&tip));
+
+        // A longer fork leaves us several blocks behind the new tip, until we catch up with it again
+        let (_, fork) = extend(&archive, Some((1, second)), 4, 1);
+        assert_eq!(next_tip(), hex::encode(&fork));
+        assert!(wait_for(&synced, &fork));
+        slurp.disconnect();
+        assert!(received.try_iter().all(|payload| payload["event"] != "tip"));
+    }
+
+    #[test]
+    fn peers_that_go_away_at_the_tip_are_cleaned_up() {
+        let served = TempDir::new("server");
+        let index = Arc::new(Index::open(served.path(), MAINNET_MAGIC, false).unwrap());
+        let archive = Archive::open_raw(served.path(), Layout::Files).unwrap().indexed(index.clone());
+        extend(&archive, None, 2, 0);
+        let (tip, _) = index.tip().unwrap().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = Arc::new(Server::new(archive, index, MAINNET_MAGIC));
+        let serving = thread::spawn(move || server.serve_peer("peer", stream));
+
+        let mut plexer = StdPlexer::new(Bearer::Tcp(client.try_clone().unwrap()));
+        let channel0 = plexer.use_channel(0);
+        let channel2 = plexer.use_channel(2);
+        plexer.muxer.spawn();
+        plexer.demuxer.spawn();
+        let versions = handshake::n2n::VersionTable::v7_and_above(MAINNET_MAGIC);
+        assert!(matches!(handshake::N2NClient::new(channel0).handshake(versions).unwrap(), handshake::Confirmation::Accepted(..)));
+        let mut chainsync = chainsync::N2NClient::new(channel2);
+        chainsync.find_intersect(vec![tip]).unwrap();
+        assert!(matches!(chainsync.request_next().unwrap(), chainsync::NextResponse::RollBackward(..)));
+        assert!(matches!(chainsync.request_next().unwrap(), chainsync::NextResponse::Await));
+
+        client.shutdown(Shutdown::Both).unwrap();
+        let started = Instant::now();
+        while !serving.is_finished() {
+            assert!(started.elapsed() < TIP_POLL_INTERVAL * 10, "still serving a peer that went away");
+            thread::sleep(Duration::from_millis(50));
+        }
+        // Block-fetch finds the connection closed too, which is reported, but nothing is left running
+        let _ = serving.join().unwrap();
+    }
+}
@@ -1,82 +1,102 @@
 use std::{
+    net::{Shutdown, SocketAddr},
     path::PathBuf,
     sync::{mpsc::{self, Receiver}, Mutex, Arc},
-    thread, fs, collections::VecDeque,
+    thread,
+    time::{Duration, Instant},
 };
 
+use anyhow::bail;
+
 use pallas::network::{
-    miniprotocols::{handshake, Point, MAINNET_MAGIC},
+    miniprotocols::{chainsync::Tip, handshake, Point, MAINNET_MAGIC},
     multiplexer::{bearers::Bearer, StdChannel, StdPlexer},
 };
 
-use crate::{body_slurp::BodySlurp, header_slurp::HeaderSlurp, cursor::Cursor};
+use crate::{body_slurp::{Batch, BodySlurp}, events::Events, keepalive::{self, KEEPALIVE_CHANNEL}, webhooks::Webhooks, header_slurp::HeaderSlurp, cursor::Cursor, stats::PeerStats, storage::Archive};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Slurp {
-    pub directory: PathBuf,
+    pub address: SocketAddr,
     pub relay: String,
     pub magic: Option<u64>,
 
-    receiver: Option<Receiver<(Point, Point)>>,
+    keepalive_interval: Duration,
+    receiver: Option<Receiver<Batch>>,
+    bearer: Option<Bearer>,
+    stats_mutex: Arc<Mutex<PeerStats>>,
+    cursor_mutex: Arc<Mutex<Cursor>>,
     headers: HeaderSlurp,
     bodies: BodySlurp,
 }
 
 impl Slurp {
-    pub fn new(directory: PathBuf, relay: String, default_point: Option<Point>, magic: Option<u64>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        directory: PathBuf,
+        archive: Archive,
+        address: SocketAddr,
+        default_point: Option<Point>,
+        magic: Option<u64>,
+        frontier_mutex: Arc<Mutex<Cursor>>,
+        events: Arc<Events>,
+        webhooks: Arc<Webhooks>,
+        keepalive_interval: Duration,
+        stats_mutex: Arc<Mutex<PeerStats>>,
+    ) -> Self {
         let (sender, receiver) = mpsc::sync_channel(10);
+        // Cursors are kept per peer, rather than per hostname, since each peer may be at a different point in the chain
+        let relay = address.to_string();
 
-        fs::create_dir_all(directory.join("cursors")).expect("unable to create cursor directory");
-
-        let cursor_file = directory.join("cursors").join(&relay);
-        let cursor = if cursor_file.exists() {
-            log::info!(target: &relay[..11], "reading cursor file");
-            let cursor_contents = fs::read(cursor_file).expect("unable to load cursor file");
-            minicbor::decode(&cursor_contents[..]).expect("unable to parse cursor")
-        } else if let Some(default_point) = default_point.clone() {
-            log::info!(target: &relay[..11], "syncing from default point {:?}", &default_point);
-            Cursor { points: VecDeque::<_>::from([default_point.into()]) }
-        } else {
-            log::info!(target: &relay[..11], "syncing from origin");
-            Cursor { points: VecDeque::<_>::from([Point::Origin.into()]) }
-        };
+        let cursor = Cursor::resume(&directory, &relay, magic.unwrap_or(MAINNET_MAGIC), &frontier_mutex, default_point)
+            .expect("unable to load cursor file");
 
         let cursor_mutex = Arc::new(Mutex::new(cursor));
-        let headers = HeaderSlurp::new(relay.clone(), directory.clone(), 5, cursor_mutex.clone(), sender);
-        let bodies = BodySlurp::new(relay.clone(), directory.clone(), cursor_mutex.clone());
+        let headers = HeaderSlurp::new(relay.clone(), archive.clone(), 5, cursor_mutex.clone(), stats_mutex.clone(), sender, webhooks);
+        let bodies = BodySlurp::new(relay.clone(), directory.clone(), archive.bodies, cursor_mutex.clone(), frontier_mutex, stats_mutex.clone(), events);
 
         Self {
-            directory: directory,
-            relay: relay,
+            address,
+            relay,
             receiver: Some(receiver),
+            bearer: None,
+            stats_mutex,
+            cursor_mutex,
             magic,
+            keepalive_interval,
             headers,
             bodies,
         }
     }
 
-    fn do_handshake(&self, channel: StdChannel) {
+    fn do_handshake(&self, channel: StdChannel) -> anyhow::Result<()> {
         let mut client = handshake::N2NClient::new(channel);
 
         let confirmation = client
-            .handshake(handshake::n2n::VersionTable::v7_and_above(self.magic.unwrap_or(MAINNET_MAGIC)))
-            .unwrap();
+            .handshake(handshake::n2n::VersionTable::v7_and_above(self.magic.unwrap_or(MAINNET_MAGIC)))?;
 
         match confirmation {
             handshake::Confirmation::Accepted(v, _) => {
-                log::info!(target: &self.relay[..11], "hand-shake accepted, using version {}", v)
+                log::info!(target: &self.relay, "hand-shake accepted, using version {}", v);
+                Ok(())
             }
             handshake::Confirmation::Rejected(x) => {
-                log::info!(target: &self.relay[..11], "hand-shake rejected with reason {:?}", x)
+                bail!("hand-shake rejected with reason {:?}", x)
             }
         }
     }
 
     pub fn slurp(&mut self) -> anyhow::Result<()> {
-        log::info!(target: &self.relay[..11], "starting slurp for a relay");
+        log::info!(target: &self.relay, "starting slurp for a relay");
 
         // setup a TCP socket to act as data bearer between our agents and the remote
         // relay.
-        let bearer = Bearer::connect_tcp(self.relay.clone())?;
+        let started = Instant::now();
+        let bearer = Bearer::connect_tcp_timeout(&self.address, CONNECT_TIMEOUT).inspect_err(|_| {
+            self.stats_mutex.lock().expect("unable to acquire lock").failed();
+        })?;
+        self.bearer = Some(bearer.clone());
 
         // setup the multiplexer by specifying the bearer and the IDs of the
         // miniprotocols to use
@@ -84,19 +104,69 @@ impl Slurp {
         let channel0 = plexer.use_channel(0);
         let channel2 = plexer.use_channel(2);
         let channel3 = plexer.use_channel(3);
+        let channel8 = plexer.use_channel(KEEPALIVE_CHANNEL);
 
         plexer.muxer.spawn();
         plexer.demuxer.spawn();
 
         // execute the required handshake against the relay
-        self.do_handshake(channel0);
+        let handshake = self.do_handshake(channel0);
+        {
+            let mut stats = self.stats_mutex.lock().expect("unable to acquire lock");
+            match handshake {
+                Ok(()) => stats.connected(started.elapsed()),
+                Err(_) => stats.failed(),
+            }
+        }
+        handshake?;
 
         // execute the chainsync flow from an arbitrary point in the chain
-        self.headers.slurp(channel2).expect("unable to start slurping headers");
+        self.headers.slurp(channel2)?;
         self.bodies.slurp(channel3, self.receiver.take().unwrap());
+        keepalive::spawn(self.relay.clone(), channel8, self.keepalive_interval, self.stats_mutex.clone());
         Ok(())
     }
 
+    /// The latest point we've downloaded from the peer, and the tip it last told us about
+    pub fn position(&self) -> (Option<Point>, Option<Tip>) {
+        let cursor = self.cursor_mutex.lock().expect("unable to acquire lock");
+        (cursor.points.front().cloned().map(Point::from), cursor.tip.clone().map(Tip::from))
+    }
+
+    /// Whether we've stopped slurping from this peer, either because the connection dropped or something went wrong
+    pub fn is_finished(&self) -> bool {
+        self.headers.is_finished() || self.bodies.is_finished()
+    }
+
+    /// Stop asking the peer for new headers, but finish downloading the bodies we've already been told about
+    pub fn drain(&mut self) {
+        log::info!(target: &self.relay, "draining");
+        self.headers.stop();
+    }
+
+    /// Whether everything has wound down after a call to drain
+    pub fn is_drained(&self) -> bool {
+        self.headers.is_finished() && self.bodies.is_finished()
+    }
+
+    /// Close the connection to the peer, without waiting for everything to wind down; see [Slurp::is_drained]
+    pub fn close(&mut self) {
+        self.bodies.stop();
+        if let Some(Bearer::Tcp(stream)) = self.bearer.take() {
+            // This will fail if the connection is already closed, which is fine
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        self.stats_mutex.lock().expect("unable to acquire lock").disconnected();
+    }
+
+    /// Close the connection to the peer, and wait for everything to wind down
+    pub fn disconnect(&mut self) {
+        self.close();
+        if self.join().is_err() {
+            log::warn!(target: &self.relay, "slurp thread panicked");
+        }
+    }
+
     pub fn join(&mut self) -> thread::Result<()> {
         self.headers.join()?;
         self.bodies.join()?;
@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::network::EXPECTED_BLOCK_INTERVAL;
+
+/// How long a peer can take to answer a chainsync request before the connection counts as stuck: at the tip, long
+/// enough that such a gap between blocks is vanishingly unlikely, and while catching up, a few block intervals
+const TIP_SILENCE_LIMIT: Duration = Duration::from_secs(EXPECTED_BLOCK_INTERVAL.as_secs() * 15);
+const SYNC_SILENCE_LIMIT: Duration = Duration::from_secs(EXPECTED_BLOCK_INTERVAL.as_secs() * 3);
+
+/// The stats of every peer we've tried, shared so they can be reported on
+pub type PeerStatsMap = Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<PeerStats>>>>>;
+
+/// What we've observed about a peer, across every connection we've made to it, used to decide which peers are worth keeping
+#[derive(Default)]
+pub struct PeerStats {
+    /// How many times we've completed a handshake with the peer
+    pub handshakes: u32,
+    /// How many times connecting or handshaking with the peer has failed
+    pub failed_handshakes: u32,
+    /// How long the most recent handshake took to complete
+    pub latency: Option<Duration>,
+    /// How many block bodies we've downloaded from the peer
+    pub blocks: u64,
+    /// How many times the peer has rolled us back
+    pub rollbacks: u64,
+    /// How many times we've given up on a connection to the peer for going quiet
+    pub stalls: u64,
+    /// How long the peer took to answer the most recent keep-alive
+    pub keepalive_rtt: Option<Duration>,
+
+    /// Time spent connected during previous connections
+    connected_for: Duration,
+    /// When the current connection was established
+    connected_at: Option<Instant>,
+    /// When we last downloaded a block body from the peer
+    last_block_at: Option<Instant>,
+    /// When the peer last sent us a chainsync message
+    last_chainsync_at: Option<Instant>,
+    /// When we asked the peer for the chainsync message we're waiting on, if we are
+    chainsync_since: Option<Instant>,
+    /// When we asked the peer for the blocks we're waiting on, if we are
+    fetching_since: Option<Instant>,
+    /// When we sent the keep-alive we're waiting on an answer to, if we are
+    keepalive_since: Option<Instant>,
+    /// Whether we're caught up with the peer's tip on the current connection
+    at_tip: bool,
+}
+
+impl PeerStats {
+    pub fn connected(&mut self, latency: Duration) {
+        self.handshakes += 1;
+        self.latency = Some(latency);
+        self.connected_at = Some(Instant::now());
+        self.at_tip = false;
+        self.chainsync_since = None;
+        self.fetching_since = None;
+        self.keepalive_since = None;
+    }
+
+    pub fn requested_chainsync(&mut self) {
+        self.chainsync_since = Some(Instant::now());
+    }
+
+    pub fn received_chainsync(&mut self) {
+        self.last_chainsync_at = Some(Instant::now());
+        self.chainsync_since = None;
+    }
+
+    pub fn reached_tip(&mut self) {
+        self.at_tip = true;
+    }
+
+    pub fn fell_behind(&mut self) {
+        self.at_tip = false;
+    }
+
+    pub fn fetching(&mut self) {
+        self.fetching_since = Some(Instant::now());
+    }
+
+    pub fn fetched(&mut self) {
+        self.fetching_since = None;
+    }
+
+    pub fn keepalive_sent(&mut self) {
+        self.keepalive_since = Some(Instant::now());
+    }
+
+    pub fn keepalive_answered(&mut self, rtt: Duration) {
+        self.keepalive_since = None;
+        self.keepalive_rtt = Some(rtt);
+    }
+
+    pub fn failed(&mut self) {
+        self.failed_handshakes += 1;
+    }
+
+    pub fn received_block(&mut self) {
+        self.blocks += 1;
+        self.last_block_at = Some(Instant::now());
+    }
+
+    /// How long it's been since the peer last gave us a block, or since we connected if it hasn't on this connection
+    pub fn since_last_block(&self) -> Option<Duration> {
+        let connected_at = self.connected_at?;
+        Some(self.last_block_at.map_or(connected_at, |b| b.max(connected_at)).elapsed())
+    }
+
+    /// How long it's been since the peer last sent us a chainsync message, or since we connected if it hasn't
+    pub fn since_last_chainsync(&self) -> Option<Duration> {
+        let connected_at = self.connected_at?;
+        Some(self.last_chainsync_at.map_or(connected_at, |c| c.max(connected_at)).elapsed())
+    }
+
+    /// If the connection looks stuck, how long it's been quiet for: either the peer hasn't answered our last
+    /// chainsync request in longer than we'd expect to wait for a block, or blocks or a keep-alive we asked for haven't
+    /// come back
+    ///
+    /// Chainsync only counts while we're waiting on the peer: when we hold off asking for more headers, because the
+    /// bodies are backed up behind a slow event sink, say, the silence is our own doing.
+    pub fn stuck_for(&self) -> Option<Duration> {
+        self.connected_at?;
+        if let Some(since) = self.chainsync_since {
+            let quiet = since.elapsed();
+            if quiet > if self.at_tip { TIP_SILENCE_LIMIT } else { SYNC_SILENCE_LIMIT } {
+                return Some(quiet);
+            }
+        }
+        [self.fetching_since, self.keepalive_since]
+            .into_iter()
+            .flatten()
+            .map(|since| since.elapsed())
+            .filter(|waiting| *waiting > SYNC_SILENCE_LIMIT)
+            .max()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected_at.is_some()
+    }
+
+    pub fn at_tip(&self) -> bool {
+        self.at_tip
+    }
+
+    pub fn disconnected(&mut self) {
+        if let Some(connected_at) = self.connected_at.take() {
+            self.connected_for += connected_at.elapsed();
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.connected_for + self.connected_at.map_or(Duration::ZERO, |c| c.elapsed())
+    }
+
+    pub fn blocks_per_second(&self) -> f64 {
+        let uptime = self.uptime().as_secs_f64();
+        if uptime > 0.0 {
+            self.blocks as f64 / uptime
+        } else {
+            0.0
+        }
+    }
+
+    /// A relative measure of how useful the peer has been; higher is better
+    ///
+    /// Latency is taken from the latest keep-alive if there has been one, since it's measured over the connection
+    /// we're using, and from the handshake otherwise. Peers we haven't tried yet score `None`, so that callers can
+    /// decide how optimistic to be about them
+    pub fn score(&self) -> Option<f64> {
+        let attempts = self.handshakes + self.failed_handshakes;
+        if attempts == 0 {
+            return None;
+        }
+        let success_rate = self.handshakes as f64 / attempts as f64;
+        let latency = self.keepalive_rtt.or(self.latency).map_or(1.0, |l| l.as_secs_f64());
+        let rollback_rate = self.rollbacks as f64 / self.blocks.max(1) as f64;
+        Some(success_rate * (1.0 + self.blocks_per_second()) / (1.0 + latency) / (1.0 + 10.0 * rollback_rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_keepalives_lower_the_score() {
+        let mut stats = PeerStats::default();
+        stats.connected(Duration::from_millis(50));
+        let handshaken = stats.score().unwrap();
+
+        stats.keepalive_rtt = Some(Duration::from_millis(50));
+        assert_eq!(stats.score(), Some(handshaken));
+        stats.keepalive_rtt = Some(Duration::from_secs(2));
+        assert!(stats.score().unwrap() < handshaken);
+    }
+
+    #[test]
+    fn only_unanswered_chainsync_requests_count_as_stuck() {
+        let mut stats = PeerStats::default();
+        stats.connected(Duration::from_millis(10));
+        let long_ago = Instant::now() - SYNC_SILENCE_LIMIT * 2;
+        stats.connected_at = Some(long_ago);
+        stats.last_chainsync_at = Some(long_ago);
+        // Holding off asking for more, while the bodies catch up
+        assert_eq!(stats.stuck_for(), None);
+
+        stats.chainsync_since = Some(long_ago);
+        assert!(stats.stuck_for().is_some_and(|quiet| quiet >= SYNC_SILENCE_LIMIT * 2));
+        stats.reached_tip();
+        assert_eq!(stats.stuck_for(), None);
+
+        stats.received_chainsync();
+        stats.requested_chainsync();
+        assert_eq!(stats.stuck_for(), None);
+    }
+}
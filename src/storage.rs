@@ -0,0 +1,666 @@
+use std::{
+    collections::VecDeque,
+    fmt, fs,
+    fs::File,
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use pallas::network::miniprotocols::Point;
+
+use crate::{
+    compression::{CompressedStorage, Compression},
+    index::{Artifact, Index, IndexedStorage},
+    transactions::TransactionStorage,
+    utils::{artifact_path, buckets_before, parse_artifact_name},
+};
+
+/// Somewhere to keep artifacts (headers or bodies), each saved under the point it belongs at
+pub trait Storage: Send + Sync {
+    /// Save an artifact; saving one we already have does nothing
+    fn write(&self, point: &Point, bytes: &[u8]) -> anyhow::Result<()>;
+
+    /// Save several artifacts together, such as a range of blocks fetched at once; by default, one at a time
+    fn write_batch(&self, artifacts: &[(Point, Vec<u8>)]) -> anyhow::Result<()> {
+        artifacts.iter().try_for_each(|(point, bytes)| self.write(point, bytes))
+    }
+
+    /// Read back an artifact, if we have one at that point
+    fn read(&self, point: &Point) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn contains(&self, point: &Point) -> bool;
+
+    /// The points we have artifacts for, at or before `max_slot`, newest first
+    fn points_before(&self, max_slot: u64) -> Box<dyn Iterator<Item = Point> + '_>;
+
+    /// Every point we have an artifact for, oldest first
+    fn points(&self) -> Box<dyn Iterator<Item = Point> + '_>;
+
+    /// Where an artifact is kept on disk, if we have one at that point
+    fn location(&self, point: &Point) -> Option<ArtifactLocation>;
+
+    /// The artifact with the highest slot at or before `max_slot`
+    fn before(&self, max_slot: u64) -> Option<Point> {
+        self.points_before(max_slot).next()
+    }
+}
+
+/// The file an artifact is kept in, and the range of bytes in that file that it takes up
+///
+/// Bodies in a compressed archive are compressed on disk, so these are the compressed bytes
+#[derive(Clone, Debug)]
+pub struct ArtifactLocation {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// How an archive lays out its artifacts on disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// One file per header and per body, in bucketed directories
+    Files,
+    /// Artifacts appended to large segment files, with an index alongside each
+    Segments,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layout::Files => write!(f, "files"),
+            Layout::Segments => write!(f, "segments"),
+        }
+    }
+}
+
+const SEGMENTS_NAME: &str = "segments";
+const MIGRATING_NAME: &str = "migrating";
+
+impl Layout {
+    /// The directories under the archive that hold artifacts in this layout
+    fn directories(self) -> &'static [&'static str] {
+        match self {
+            Layout::Files => &["headers", "bodies"],
+            Layout::Segments => &[SEGMENTS_NAME],
+        }
+    }
+
+    fn exists_in(self, directory: &Path) -> bool {
+        self.directories().iter().any(|d| directory.join(d).exists())
+    }
+
+    /// The layout an existing archive uses, or None if nothing has been saved to it yet
+    pub fn detect(directory: &Path) -> Option<Layout> {
+        [Layout::Segments, Layout::Files].into_iter().find(|layout| layout.exists_in(directory))
+    }
+}
+
+/// The headers and bodies of an archive, wherever they are kept
+#[derive(Clone)]
+pub struct Archive {
+    pub headers: Arc<dyn Storage>,
+    pub bodies: Arc<dyn Storage>,
+}
+
+impl Archive {
+    /// Open an archive, compressing and decompressing bodies as its settings say; see [Compression]
+    pub fn open(directory: &Path, layout: Layout) -> anyhow::Result<Archive> {
+        let archive = Archive::open_raw(directory, layout)?;
+        let compression = Arc::new(Compression::load(directory)?);
+        Ok(Archive {
+            headers: archive.headers,
+            bodies: Arc::new(CompressedStorage::new(archive.bodies, compression)),
+        })
+    }
+
+    /// Keep an index up to date with everything saved to the archive from now on; see [Index]
+    pub fn indexed(self, index: Arc<Index>) -> Archive {
+        Archive {
+            headers: Arc::new(IndexedStorage::new(self.headers, index.clone(), Artifact::Header)),
+            bodies: Arc::new(IndexedStorage::new(self.bodies, index, Artifact::Body)),
+        }
+    }
+
+    /// Save each transaction in the bodies saved to the archive from now on too; see [TransactionStorage]
+    pub fn with_transactions(self, directory: &Path) -> Archive {
+        Archive {
+            headers: self.headers,
+            bodies: Arc::new(TransactionStorage::new(self.bodies, directory)),
+        }
+    }
+
+    /// Open an archive, reading and writing artifacts exactly as they are stored
+    pub fn open_raw(directory: &Path, layout: Layout) -> anyhow::Result<Archive> {
+        Ok(match layout {
+            Layout::Files => Archive {
+                headers: Arc::new(FileStorage::new(directory.join("headers"))),
+                bodies: Arc::new(FileStorage::new(directory.join("bodies"))),
+            },
+            Layout::Segments => Archive {
+                headers: Arc::new(SegmentStorage::open(directory.join(SEGMENTS_NAME).join("headers"))?),
+                bodies: Arc::new(SegmentStorage::open(directory.join(SEGMENTS_NAME).join("bodies"))?),
+            },
+        })
+    }
+}
+
+/// Move an archive over to another layout, copying every artifact as it is
+pub fn migrate(directory: &Path, to: Layout) -> anyhow::Result<()> {
+    let Some(from) = [Layout::Files, Layout::Segments]
+        .into_iter()
+        .find(|layout| *layout != to && layout.exists_in(directory))
+    else {
+        bail!("there is nothing in {:?} to migrate to the {} layout", directory, to);
+    };
+    log::info!("migrating {:?} from the {} layout to the {} layout", directory, from, to);
+    let source = Archive::open_raw(directory, from)?;
+    rebuild(directory, from, &source, to, &mut |_, body| Ok(body))
+}
+
+/// Something to do to each body as an archive is rebuilt
+pub type BodyTransform<'a> = dyn FnMut(&Point, Vec<u8>) -> anyhow::Result<Vec<u8>> + 'a;
+
+/// Copy every artifact in an archive into a fresh one, passing each body through `transform_body` on the way
+///
+/// The new archive is built up alongside the old one, and only swapped in once everything has been copied,
+/// so an interrupted rebuild can just be run again. The old one is moved aside rather than deleted.
+pub fn rebuild(
+    directory: &Path,
+    from: Layout,
+    source: &Archive,
+    to: Layout,
+    transform_body: &mut BodyTransform,
+) -> anyhow::Result<()> {
+    let staging = directory.join(MIGRATING_NAME);
+    let destination = Archive::open_raw(&staging, to)?;
+
+    for (kind, source, destination) in [
+        ("headers", &source.headers, &destination.headers),
+        ("bodies", &source.bodies, &destination.bodies),
+    ] {
+        let mut copied = 0;
+        for point in source.points() {
+            let Some(mut bytes) = source.read(&point)? else { continue };
+            if kind == "bodies" {
+                bytes = transform_body(&point, bytes)?;
+            }
+            destination.write(&point, &bytes)?;
+            copied += 1;
+            if copied % 10_000 == 0 {
+                log::info!("copied {} {}, up to {:?}", copied, kind, point);
+            }
+        }
+        log::info!("copied {} {}", copied, kind);
+    }
+    drop(destination);
+
+    let aside = directory.join(format!("previous-{}", SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()));
+    fs::create_dir_all(&aside)?;
+    for name in from.directories() {
+        if directory.join(name).exists() {
+            fs::rename(directory.join(name), aside.join(name))?;
+        }
+    }
+    for name in to.directories() {
+        if staging.join(name).exists() {
+            fs::rename(staging.join(name), directory.join(name))?;
+        }
+    }
+    fs::remove_dir(&staging)?;
+    log::info!("done; the old archive was moved to {:?}, and can be deleted", aside);
+    Ok(())
+}
+
+/// The original layout, with one file per artifact; see [Bucketing] in utils
+pub struct FileStorage {
+    directory: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+
+    /// List the points saved in a lower bucket, newest first
+    fn bucket_points(lower_bucket: &Path) -> Vec<Point> {
+        let Ok(entries) = fs::read_dir(lower_bucket) else { return vec![] };
+        let mut points: Vec<Point> = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| parse_artifact_name(e.file_name().to_str()?))
+            .collect();
+        points.sort_by_key(|p| std::cmp::Reverse(p.slot_or_default()));
+        points
+    }
+}
+
+impl Storage for FileStorage {
+    fn write(&self, point: &Point, bytes: &[u8]) -> anyhow::Result<()> {
+        let path = artifact_path(self.directory.clone(), point.clone());
+        fs::create_dir_all(path.parent().unwrap())
+            .with_context(|| format!("unable to create directory {:?}", path.parent()))?;
+        fs::write(&path, bytes).with_context(|| format!("could not save artifact {:?}", path))?;
+        Ok(())
+    }
+
+    fn read(&self, point: &Point) -> anyhow::Result<Option<Vec<u8>>> {
+        match fs::read(artifact_path(self.directory.clone(), point.clone())) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn contains(&self, point: &Point) -> bool {
+        artifact_path(self.directory.clone(), point.clone()).exists()
+    }
+
+    fn location(&self, point: &Point) -> Option<ArtifactLocation> {
+        let path = artifact_path(self.directory.clone(), point.clone());
+        let length = fs::metadata(&path).ok()?.len();
+        Some(ArtifactLocation { path, offset: 0, length })
+    }
+
+    fn points_before(&self, max_slot: u64) -> Box<dyn Iterator<Item = Point> + '_> {
+        Box::new(
+            buckets_before(&self.directory, max_slot)
+                .into_iter()
+                .flat_map(move |(_, upper_bucket)| buckets_before(&upper_bucket, max_slot))
+                .flat_map(move |(_, lower_bucket)| FileStorage::bucket_points(&lower_bucket))
+                .filter(move |p| p.slot_or_default() <= max_slot),
+        )
+    }
+
+    fn points(&self) -> Box<dyn Iterator<Item = Point> + '_> {
+        let mut upper_buckets = buckets_before(&self.directory, u64::MAX);
+        upper_buckets.reverse();
+        Box::new(
+            upper_buckets
+                .into_iter()
+                .flat_map(|(_, upper_bucket)| {
+                    let mut lower_buckets = buckets_before(&upper_bucket, u64::MAX);
+                    lower_buckets.reverse();
+                    lower_buckets
+                })
+                .flat_map(|(_, lower_bucket)| {
+                    let mut points = FileStorage::bucket_points(&lower_bucket);
+                    points.reverse();
+                    points
+                }),
+        )
+    }
+}
+
+// [Segments]: Artifacts are appended to {directory}/{segment}.seg, rolling over to a new segment once
+// one reaches SEGMENT_SIZE. For each artifact, a fixed size entry is appended to {segment}.idx:
+// the slot (u64), the hash (32 bytes), then the offset (u64) and length (u32) of the artifact in the
+// segment, all big endian. The entry is only written once the artifact is, so anything in a segment
+// past its last indexed artifact (say, from a crash) is simply ignored.
+//
+// Only the range of slots each segment covers is kept in memory. A segment's index is read from disk
+// when we need to look inside it, and the few we looked in most recently are kept around, since we
+// mostly work near the tip, or walk through the segments in order.
+
+/// How large a segment gets before we start a new one
+const SEGMENT_SIZE: u64 = 256 * 1024 * 1024;
+/// The size in bytes of each entry in a segment's index
+const INDEX_ENTRY_SIZE: usize = 52;
+/// How many segment indexes we keep read into memory at once
+const CACHED_SEGMENTS: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    segment: u32,
+    offset: u64,
+    length: u32,
+}
+
+/// An artifact from a segment's index
+#[derive(Clone, Debug)]
+struct SegmentEntry {
+    slot: u64,
+    hash: [u8; 32],
+    location: Location,
+}
+
+impl SegmentEntry {
+    fn parse(segment: u32, bytes: &[u8]) -> Self {
+        SegmentEntry {
+            slot: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            hash: bytes[8..40].try_into().unwrap(),
+            location: Location {
+                segment,
+                offset: u64::from_be_bytes(bytes[40..48].try_into().unwrap()),
+                length: u32::from_be_bytes(bytes[48..52].try_into().unwrap()),
+            },
+        }
+    }
+}
+
+/// The entries of a segment's index, sorted by slot
+type SegmentIndex = Arc<Vec<SegmentEntry>>;
+
+/// The lowest and highest slots of the artifacts in a segment
+#[derive(Clone, Copy, Debug)]
+struct SegmentRange {
+    segment: u32,
+    first_slot: u64,
+    last_slot: u64,
+}
+
+/// The segment currently being appended to
+struct SegmentWriter {
+    segment: u32,
+    data: File,
+    index: File,
+    size: u64,
+}
+
+/// Artifacts packed into large append-only segment files, with the index of each read from disk as needed
+pub struct SegmentStorage {
+    directory: PathBuf,
+    ranges: RwLock<Vec<SegmentRange>>,
+    /// The most recently used segment indexes, most recent first
+    cache: Mutex<VecDeque<(u32, SegmentIndex)>>,
+    writer: Mutex<Option<SegmentWriter>>,
+    next_segment: Mutex<u32>,
+}
+
+impl SegmentStorage {
+    pub fn open(directory: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&directory).with_context(|| format!("unable to create directory {:?}", directory))?;
+
+        let mut segments: Vec<u32> = fs::read_dir(&directory)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str()?.strip_suffix(".idx")?.parse::<u32>().ok())
+            .collect();
+        segments.sort();
+
+        // Stream through each index for the slots it covers, rather than holding on to it
+        let mut ranges = vec![];
+        for segment in &segments {
+            let path = SegmentStorage::path(&directory, *segment, "idx");
+            let file = File::open(&path).with_context(|| format!("unable to read segment index {:?}", path))?;
+            let mut reader = BufReader::new(file);
+            let mut entry = [0; INDEX_ENTRY_SIZE];
+            let mut range: Option<SegmentRange> = None;
+            loop {
+                // A partially written entry at the end means we crashed mid-write, so its artifact is ignored
+                match reader.read_exact(&mut entry) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e).with_context(|| format!("unable to read segment index {:?}", path)),
+                }
+                let slot = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+                let range = range.get_or_insert(SegmentRange { segment: *segment, first_slot: slot, last_slot: slot });
+                range.first_slot = range.first_slot.min(slot);
+                range.last_slot = range.last_slot.max(slot);
+            }
+            ranges.extend(range);
+        }
+
+        // Carry on appending to the newest segment, unless it's already full
+        let next_segment = match segments.last() {
+            Some(&last) => match fs::metadata(SegmentStorage::path(&directory, last, "seg")) {
+                Ok(metadata) if metadata.len() < SEGMENT_SIZE => last,
+                _ => last + 1,
+            },
+            None => 0,
+        };
+
+        Ok(Self {
+            directory,
+            ranges: RwLock::new(ranges),
+            cache: Mutex::new(VecDeque::new()),
+            writer: Mutex::new(None),
+            next_segment: Mutex::new(next_segment),
+        })
+    }
+
+    fn path(directory: &Path, segment: u32, extension: &str) -> PathBuf {
+        directory.join(format!("{:06}.{}", segment, extension))
+    }
+
+    /// The entries of a segment's index, read from disk unless we have them already
+    fn segment_index(&self, segment: u32) -> anyhow::Result<SegmentIndex> {
+        // Writers hold this while appending to an index, so what we read matches what they've cached
+        let mut cache = self.cache.lock().expect("unable to acquire lock");
+        if let Some(position) = cache.iter().position(|(s, _)| *s == segment) {
+            let cached = cache.remove(position).unwrap();
+            cache.push_front(cached.clone());
+            return Ok(cached.1);
+        }
+
+        let path = SegmentStorage::path(&self.directory, segment, "idx");
+        let bytes = fs::read(&path).with_context(|| format!("unable to read segment index {:?}", path))?;
+        let mut entries: Vec<SegmentEntry> =
+            bytes.chunks_exact(INDEX_ENTRY_SIZE).map(|entry| SegmentEntry::parse(segment, entry)).collect();
+        // Artifacts are mostly appended in slot order already, and a stable sort keeps any at the same slot in the
+        // order they were saved
+        entries.sort_by_key(|e| e.slot);
+        let entries = Arc::new(entries);
+        cache.push_front((segment, entries.clone()));
+        cache.truncate(CACHED_SEGMENTS);
+        Ok(entries)
+    }
+
+    /// The entries of each segment's index that might hold something at the given slot
+    fn segments_at(&self, slot: u64) -> anyhow::Result<Vec<SegmentIndex>> {
+        let ranges: Vec<SegmentRange> = self.ranges.read().expect("unable to acquire lock").clone();
+        ranges
+            .iter()
+            .filter(|r| r.first_slot <= slot && slot <= r.last_slot)
+            .map(|r| self.segment_index(r.segment))
+            .collect()
+    }
+
+    fn locate(&self, point: &Point) -> anyhow::Result<Option<Location>> {
+        let Point::Specific(slot, hash) = point else { return Ok(None) };
+        for entries in self.segments_at(*slot)? {
+            let start = entries.partition_point(|e| e.slot < *slot);
+            let found = entries[start..].iter().take_while(|e| e.slot == *slot).find(|e| e.hash[..] == hash[..]);
+            if let Some(entry) = found {
+                return Ok(Some(entry.location));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Open the next segment to append to
+    fn roll(&self) -> anyhow::Result<SegmentWriter> {
+        let mut next_segment = self.next_segment.lock().expect("unable to acquire lock");
+        let segment = *next_segment;
+        *next_segment += 1;
+
+        let open = |extension| {
+            File::options()
+                .create(true)
+                .append(true)
+                .open(SegmentStorage::path(&self.directory, segment, extension))
+        };
+        let data = open("seg")?;
+        let index = open("idx")?;
+        // If the previous run crashed before indexing something, it's dead weight we append after,
+        // but a partially written index entry has to go, or it would throw off every entry after it
+        let index_size = index.metadata()?.len();
+        index.set_len(index_size - index_size % INDEX_ENTRY_SIZE as u64)?;
+        let size = data.metadata()?.len();
+        Ok(SegmentWriter { segment, data, index, size })
+    }
+
+    /// The next run of points from `slot` onwards, looking down from it if `descending` and up otherwise, along
+    /// with the slot to carry on from afterwards, if there's anything left
+    ///
+    /// Segments mostly cover separate ranges of slots, so a run is usually everything left in the nearest segment.
+    fn next_points(&self, slot: u64, descending: bool) -> anyhow::Result<(Vec<Point>, Option<u64>)> {
+        let ranges: Vec<SegmentRange> = self.ranges.read().expect("unable to acquire lock").clone();
+        // The closest slot each segment could possibly have, nearest first
+        let mut candidates: Vec<(u64, u32)> = ranges
+            .iter()
+            .filter_map(|r| match descending {
+                true if r.first_slot <= slot => Some((r.last_slot.min(slot), r.segment)),
+                false if slot <= r.last_slot => Some((r.first_slot.max(slot), r.segment)),
+                _ => None,
+            })
+            .collect();
+        candidates.sort_by_key(|(bound, _)| if descending { u64::MAX - bound } else { *bound });
+        let point = |e: &SegmentEntry| Point::Specific(e.slot, e.hash.to_vec());
+
+        let Some(&(bound, segment)) = candidates.first() else { return Ok((vec![], None)) };
+        // Anything nearer than the next segment could be can only be in this one
+        let run_end = candidates.get(1).map(|(b, _)| *b);
+        if run_end != Some(bound) {
+            let entries = self.segment_index(segment)?;
+            let run = if descending {
+                let from = run_end.map(|end| entries.partition_point(|e| e.slot <= end)).unwrap_or(0);
+                entries[from..entries.partition_point(|e| e.slot <= slot)].iter().rev().map(point).collect()
+            } else {
+                let to = run_end.map(|end| entries.partition_point(|e| e.slot < end)).unwrap_or(entries.len());
+                entries[entries.partition_point(|e| e.slot < slot)..to].iter().map(point).collect()
+            };
+            return Ok((run, run_end));
+        }
+
+        // Segments overlap here, so take a single slot at a time from all of them until they don't
+        let mut nearest: Option<(u64, Vec<Point>)> = None;
+        for (bound, segment) in candidates {
+            let beaten = |nearest_slot: u64| if descending { bound < nearest_slot } else { bound > nearest_slot };
+            if nearest.as_ref().is_some_and(|(nearest_slot, _)| beaten(*nearest_slot)) {
+                break;
+            }
+            let entries = self.segment_index(segment)?;
+            let found = if descending {
+                entries[..entries.partition_point(|e| e.slot <= slot)].last()
+            } else {
+                entries.get(entries.partition_point(|e| e.slot < slot))
+            };
+            let Some(found) = found.map(|e| e.slot) else { continue };
+            let points = entries[entries.partition_point(|e| e.slot < found)..]
+                .iter()
+                .take_while(|e| e.slot == found)
+                .map(point);
+            match &mut nearest {
+                Some((nearest_slot, nearest_points)) if *nearest_slot == found => nearest_points.extend(points),
+                Some((nearest_slot, _)) if (found < *nearest_slot) == descending => {}
+                _ => nearest = Some((found, points.collect())),
+            }
+        }
+        Ok(match nearest {
+            Some((found, points)) => (points, if descending { found.checked_sub(1) } else { found.checked_add(1) }),
+            None => (vec![], None),
+        })
+    }
+
+    /// Walk the points a run at a time, so we never hold a lock while the caller is busy
+    fn walk(&self, from: u64, descending: bool) -> Box<dyn Iterator<Item = Point> + '_> {
+        let mut pending = VecDeque::new();
+        let mut next = Some(from);
+        Box::new(std::iter::from_fn(move || loop {
+            if let Some(point) = pending.pop_front() {
+                return Some(point);
+            }
+            let (points, after) = match self.next_points(next?, descending) {
+                Ok(run) => run,
+                Err(e) => {
+                    log::warn!("unable to read segment index: {:#}", e);
+                    return None;
+                }
+            };
+            pending = points.into();
+            next = after;
+        }))
+    }
+}
+
+impl Storage for SegmentStorage {
+    fn write(&self, point: &Point, bytes: &[u8]) -> anyhow::Result<()> {
+        let Point::Specific(slot, hash) = point else { bail!("can't save an artifact at origin") };
+        let mut writer = self.writer.lock().expect("unable to acquire lock");
+        // Several peers will often send us the same thing, so check again now that we're the only writer
+        if self.locate(point)?.is_some() {
+            return Ok(());
+        }
+
+        let current = match writer.take() {
+            Some(current) if current.size < SEGMENT_SIZE => current,
+            _ => self.roll()?,
+        };
+        let current = writer.insert(current);
+
+        let location = Location {
+            segment: current.segment,
+            offset: current.size,
+            length: bytes.len().try_into()?,
+        };
+        current.data.write_all(bytes)?;
+        current.size += bytes.len() as u64;
+
+        let mut entry = Vec::with_capacity(INDEX_ENTRY_SIZE);
+        entry.extend_from_slice(&slot.to_be_bytes());
+        entry.extend_from_slice(hash);
+        entry.extend_from_slice(&location.offset.to_be_bytes());
+        entry.extend_from_slice(&location.length.to_be_bytes());
+        let mut cache = self.cache.lock().expect("unable to acquire lock");
+        current.index.write_all(&entry)?;
+
+        let entry = SegmentEntry { slot: *slot, hash: hash[..].try_into()?, location };
+        // Keep our copy of the segment's index up to date, if we have one, rather than reading it all again
+        if let Some((_, entries)) = cache.iter_mut().find(|(s, _)| *s == current.segment) {
+            let entries = Arc::make_mut(entries);
+            let position = entries.partition_point(|e| e.slot <= *slot);
+            entries.insert(position, entry);
+        }
+        drop(cache);
+        let mut ranges = self.ranges.write().expect("unable to acquire lock");
+        match ranges.iter_mut().find(|r| r.segment == current.segment) {
+            Some(range) => {
+                range.first_slot = range.first_slot.min(*slot);
+                range.last_slot = range.last_slot.max(*slot);
+            }
+            None => ranges.push(SegmentRange { segment: current.segment, first_slot: *slot, last_slot: *slot }),
+        }
+        Ok(())
+    }
+
+    fn read(&self, point: &Point) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(location) = self.locate(point)? else { return Ok(None) };
+        let path = SegmentStorage::path(&self.directory, location.segment, "seg");
+        let mut file = File::open(&path).with_context(|| format!("unable to open segment {:?}", path))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut bytes = vec![0; location.length as usize];
+        file.read_exact(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    fn contains(&self, point: &Point) -> bool {
+        self.location(point).is_some()
+    }
+
+    fn location(&self, point: &Point) -> Option<ArtifactLocation> {
+        let location = match self.locate(point) {
+            Ok(location) => location?,
+            Err(e) => {
+                log::warn!("unable to read segment index: {:#}", e);
+                return None;
+            }
+        };
+        Some(ArtifactLocation {
+            path: SegmentStorage::path(&self.directory, location.segment, "seg"),
+            offset: location.offset,
+            length: location.length as u64,
+        })
+    }
+
+    fn points_before(&self, max_slot: u64) -> Box<dyn Iterator<Item = Point> + '_> {
+        self.walk(max_slot, true)
+    }
+
+    fn points(&self) -> Box<dyn Iterator<Item = Point> + '_> {
+        self.walk(0, false)
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+use pallas::{
+    codec::minicbor::Encoder,
+    network::miniprotocols::{Point, MAINNET_MAGIC},
+};
+
+use serde_json::Value;
+use tiny_http::{Response, Server};
+
+use crate::{body_slurp::BodySlurp, network::Network};
+
+static NEXT_DIRECTORY: AtomicUsize = AtomicUsize::new(0);
+
+/// A scratch directory for a test, removed again when it's dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!(
+            "cardano-slurp-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT_DIRECTORY.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("unable to create test directory");
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A chain of epoch boundary blocks, one at the start of each epoch from the first, each pointing back at the one
+/// before (and the first at mainnet's genesis), as the CBOR a node would send
+///
+/// They're the smallest blocks that are still valid, so they make a chain that's cheap to build and follow.
+pub fn ebb_chain(length: u64) -> Vec<Vec<u8>> {
+    let mut previous = hex::decode(Network::from_magic(MAINNET_MAGIC).unwrap().genesis_hash).unwrap();
+    let mut blocks = vec![];
+    for epoch in 0..length {
+        let mut e = Encoder::new(vec![]);
+        e.array(2).unwrap().u16(0).unwrap().array(3).unwrap();
+        // The header: network magic, previous block, body proof, the epoch with its difficulty, and extra data
+        e.array(5).unwrap().u32(MAINNET_MAGIC as u32).unwrap();
+        e.bytes(&previous).unwrap().bytes(&[7; 32]).unwrap();
+        e.array(2).unwrap().u64(epoch).unwrap().array(1).unwrap().u64(0).unwrap();
+        e.array(1).unwrap().map(0).unwrap();
+        // The body, a list of stakeholder ids, which vary a little from block to block
+        e.array(20 + epoch % 5).unwrap();
+        for stakeholder in 0..20 + epoch % 5 {
+            e.bytes(&[(stakeholder % 7) as u8; 28]).unwrap();
+        }
+        e.array(1).unwrap().map(0).unwrap();
+        let block = e.into_writer();
+
+        let Some(Point::Specific(_, hash)) = BodySlurp::body_point(&block) else { panic!("invalid test block") };
+        previous = hash;
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// A babbage block with no transactions, as the CBOR a node would send, and its point
+///
+/// Nothing checks the signatures or the body hash, so they're filler; `issuer` varies the header, to make
+/// competing blocks on different forks.
+pub fn babbage_block(number: u64, slot: u64, previous: Option<&[u8]>, issuer: u8) -> (Point, Vec<u8>) {
+    let mut e = Encoder::new(vec![]);
+    e.array(2).unwrap().u16(6).unwrap().array(5).unwrap().array(2).unwrap();
+    // The header body: height, slot, previous block, keys, VRF result, body size and hash, operational
+    // certificate and protocol version
+    e.array(10).unwrap().u64(number).unwrap().u64(slot).unwrap();
+    match previous {
+        Some(previous) => e.bytes(previous).unwrap(),
+        None => e.null().unwrap(),
+    };
+    e.bytes(&[issuer; 32]).unwrap().bytes(&[1; 32]).unwrap();
+    e.array(2).unwrap().bytes(&[2; 32]).unwrap().bytes(&[3; 80]).unwrap();
+    e.u64(4).unwrap().bytes(&[5; 32]).unwrap();
+    e.array(4).unwrap().bytes(&[6; 32]).unwrap().u64(0).unwrap().u64(0).unwrap().bytes(&[7; 64]).unwrap();
+    e.array(2).unwrap().u64(8).unwrap().u64(0).unwrap();
+    e.bytes(&[9; 448]).unwrap();
+    // The transaction bodies, witnesses, auxiliary data and invalid transactions
+    e.array(0).unwrap().array(0).unwrap().map(0).unwrap().array(0).unwrap();
+    let block = e.into_writer();
+
+    let point = BodySlurp::body_point(&block).expect("invalid test block");
+    (point, block)
+}
+
+/// A webhook that answers with each status in turn, then 200, handing back each payload it's sent
+pub fn webhook(statuses: Vec<u16>) -> (String, mpsc::Receiver<Value>) {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut statuses = statuses.into_iter();
+        for mut request in server.incoming_requests() {
+            assert_eq!(request.url(), "/hook");
+            let content_type = request.headers().iter().find(|h| h.field.equiv("Content-Type")).map(|h| h.value.to_string());
+            assert_eq!(content_type.as_deref(), Some("application/json"));
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let _ = sender.send(serde_json::from_str(&body).unwrap());
+            let _ = request.respond(Response::empty(statuses.next().unwrap_or(200)));
+        }
+    });
+    (url, receiver)
+}
@@ -1,6 +1,17 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
 use serde::Deserialize;
 
+use crate::pool::RelayGroup;
+
+/// A producer in the legacy topology format, as produced by the topology updater; its valency is how many of the
+/// peers it resolves to to connect to
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct TopologyProducer {
     #[serde(alias="addr")]
     pub address: String,
@@ -12,16 +23,167 @@ pub struct TopologyProducer {
     pub region: Option<String>,
 }
 
+/// A single relay within a group of roots in the P2P topology format
+#[derive(Deserialize)]
+pub struct AccessPoint {
+    #[serde(alias="addr")]
+    pub address: String,
+    pub port: u32,
+}
+
+/// A group of local or public roots in the P2P topology format
 #[derive(Deserialize)]
+#[allow(dead_code)]
+pub struct RootGroup {
+    #[serde(alias="accessPoints", default)]
+    pub access_points: Vec<AccessPoint>,
+    pub advertise: Option<bool>,
+    pub trustable: Option<bool>,
+    /// The older name for `hotValency`
+    pub valency: Option<u32>,
+    #[serde(alias="hotValency")]
+    pub hot_valency: Option<u32>,
+    #[serde(alias="warmValency")]
+    pub warm_valency: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct Topology {
     #[serde(alias="resultcode")]
-    pub result_code: String,
+    pub result_code: Option<String>,
     #[serde(alias="networkMagic")]
-    pub network_magic: String,
+    pub network_magic: Option<String>,
     #[serde(alias="ipType")]
-    pub ip_type: u8,
+    pub ip_type: Option<u8>,
     #[serde(alias="requestedIpVersion")]
-    pub requested_ip_version: String,
-    #[serde(alias="Producers")]
+    pub requested_ip_version: Option<String>,
+    #[serde(alias="Producers", default)]
     pub producers: Vec<TopologyProducer>,
+    #[serde(alias="localRoots", default)]
+    pub local_roots: Vec<RootGroup>,
+    #[serde(alias="publicRoots", default)]
+    pub public_roots: Vec<RootGroup>,
+    #[serde(alias="useLedgerAfterSlot")]
+    pub use_ledger_after_slot: Option<i64>,
+}
+
+impl RootGroup {
+    /// The group's relays, with its valencies, which the pool applies to the peers they resolve to, as the node does
+    fn relays(&self) -> RelayGroup {
+        RelayGroup {
+            relays: self.access_points.iter().map(|ap| format!("{}:{}", ap.address, ap.port)).collect(),
+            hot_valency: self.hot_valency.or(self.valency).map(|v| v as usize),
+            warm_valency: self.warm_valency.map(|v| v as usize),
+        }
+    }
+}
+
+impl Topology {
+    pub fn load(path: &Path) -> anyhow::Result<Topology> {
+        let file_contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&file_contents)?)
+    }
+
+    /// All the relays described by the topology, in either format, grouped with the valency they were given
+    pub fn relays(&self) -> Vec<RelayGroup> {
+        let producers = self.producers.iter().map(|p| RelayGroup {
+            relays: vec![format!("{}:{}", p.address, p.port)],
+            hot_valency: p.valency.map(|v| v as usize),
+            warm_valency: None,
+        });
+        let roots = self
+            .local_roots
+            .iter()
+            .chain(self.public_roots.iter())
+            .map(|g| g.relays());
+        producers.chain(roots).collect()
+    }
+}
+
+/// Watches a topology file for changes, so that the relays we connect to can be updated without restarting
+pub struct TopologyWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl TopologyWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, modified: None }
+    }
+
+    /// Re-read the topology file if it has changed since we last read it
+    ///
+    /// A topology file that can't be read or parsed is reported, and otherwise ignored, so that a botched
+    /// edit doesn't disconnect us from everything
+    pub fn poll(&mut self) -> Option<Topology> {
+        let modified = match fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                log::warn!("unable to check topology file {:?}: {}", self.path, e);
+                return None;
+            }
+        };
+        if self.modified == Some(modified) {
+            return None;
+        }
+        self.modified = Some(modified);
+
+        match Topology::load(&self.path) {
+            Ok(topology) => Some(topology),
+            Err(e) => {
+                log::warn!("unable to read topology file {:?}: {}", self.path, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn p2p_groups_keep_their_valency() {
+        let topology: Topology = serde_json::from_str(
+            r#"{
+                "localRoots": [
+                    { "accessPoints": [{ "address": "10.0.0.1", "port": 3001 }, { "address": "10.0.0.2", "port": 3002 }], "advertise": false, "valency": 1 }
+                ],
+                "publicRoots": [
+                    { "accessPoints": [{ "address": "relays.example", "port": 3001 }], "advertise": false, "hotValency": 2, "warmValency": 4 },
+                    { "accessPoints": [{ "address": "more.example", "port": 3001 }], "advertise": false }
+                ],
+                "useLedgerAfterSlot": 128908821
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(topology.use_ledger_after_slot, Some(128908821));
+        let groups: Vec<_> = topology
+            .relays()
+            .into_iter()
+            .map(|g| (g.relays, g.hot_valency, g.warm_valency))
+            .collect();
+        assert_eq!(groups, [
+            (vec!["10.0.0.1:3001".to_string(), "10.0.0.2:3002".to_string()], Some(1), None),
+            (vec!["relays.example:3001".to_string()], Some(2), Some(4)),
+            (vec!["more.example:3001".to_string()], None, None),
+        ]);
+    }
+
+    #[test]
+    fn legacy_producers_are_a_group_each() {
+        let topology: Topology = serde_json::from_str(
+            r#"{
+                "resultcode": "201",
+                "Producers": [
+                    { "addr": "relays.example", "port": 3001, "valency": 2, "distance": 10, "continent": "EU" },
+                    { "addr": "10.0.0.1", "port": 6000 }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let groups: Vec<_> = topology.relays().into_iter().map(|g| (g.relays, g.hot_valency)).collect();
+        assert_eq!(groups, [(vec!["relays.example:3001".to_string()], Some(2)), (vec!["10.0.0.1:6000".to_string()], None)]);
+    }
 }
@@ -0,0 +1,90 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use pallas::{
+    codec::utils::Nullable,
+    ledger::traverse::{Era, MultiEraBlock, MultiEraTx},
+    network::miniprotocols::Point,
+};
+
+use crate::storage::{ArtifactLocation, FileStorage, Storage};
+
+pub const TRANSACTIONS_NAME: &str = "txs";
+
+/// The CBOR of a transaction on its own, as it would be submitted, made from the exact bytes it has in
+/// its block, so that its hash and witnesses still verify
+pub fn transaction_cbor(tx: &MultiEraTx) -> Vec<u8> {
+    match tx {
+        // Before alonzo, transactions have no validity flag, so they're [body, witnesses, auxiliary data]
+        MultiEraTx::AlonzoCompatible(x, era) if *era < Era::Alonzo => {
+            let mut cbor = vec![0x83];
+            cbor.extend_from_slice(x.transaction_body.raw_cbor());
+            cbor.extend_from_slice(x.transaction_witness_set.raw_cbor());
+            match &x.auxiliary_data {
+                Nullable::Some(auxiliary_data) => cbor.extend_from_slice(auxiliary_data.raw_cbor()),
+                Nullable::Null | Nullable::Undefined => cbor.push(0xf6),
+            }
+            cbor
+        }
+        // Everything else is already encoded from the original bytes of each part
+        _ => tx.encode(),
+    }
+}
+
+/// A storage for bodies that also saves each transaction in a body on its own, under {directory}/txs,
+/// bucketed by the slot of its block like any other artifact, and named {slot}-{transaction hash}
+pub struct TransactionStorage {
+    inner: Arc<dyn Storage>,
+    transactions: FileStorage,
+}
+
+impl TransactionStorage {
+    pub fn new(inner: Arc<dyn Storage>, directory: &Path) -> Self {
+        Self {
+            inner,
+            transactions: FileStorage::new(directory.join(TRANSACTIONS_NAME)),
+        }
+    }
+
+    fn save_transactions(&self, point: &Point, bytes: &[u8]) -> anyhow::Result<()> {
+        let Point::Specific(slot, hash) = point else { return Ok(()) };
+        let block = MultiEraBlock::decode(bytes)
+            .with_context(|| format!("unable to decode block {}/{}", slot, hex::encode(hash)))?;
+        for tx in block.txs() {
+            self.transactions.write(&Point::Specific(*slot, tx.hash().to_vec()), &transaction_cbor(&tx))?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for TransactionStorage {
+    fn write(&self, point: &Point, bytes: &[u8]) -> anyhow::Result<()> {
+        self.inner.write(point, bytes)?;
+        self.save_transactions(point, bytes)
+    }
+
+    fn write_batch(&self, artifacts: &[(Point, Vec<u8>)]) -> anyhow::Result<()> {
+        self.inner.write_batch(artifacts)?;
+        artifacts.iter().try_for_each(|(point, bytes)| self.save_transactions(point, bytes))
+    }
+
+    fn read(&self, point: &Point) -> anyhow::Result<Option<Vec<u8>>> {
+        self.inner.read(point)
+    }
+
+    fn contains(&self, point: &Point) -> bool {
+        self.inner.contains(point)
+    }
+
+    fn location(&self, point: &Point) -> Option<ArtifactLocation> {
+        self.inner.location(point)
+    }
+
+    fn points_before(&self, max_slot: u64) -> Box<dyn Iterator<Item = Point> + '_> {
+        self.inner.points_before(max_slot)
+    }
+
+    fn points(&self) -> Box<dyn Iterator<Item = Point> + '_> {
+        self.inner.points()
+    }
+}
@@ -1,9 +1,17 @@
-use std::path::PathBuf;
+use std::{
+    cmp::Reverse,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-use pallas::network::miniprotocols::Point;
+use pallas::{ledger::traverse::MultiEraHeader, network::miniprotocols::Point};
+use serde_json::{json, Value};
 
-const LARGE_BUCKET_SIZE: u64 = 20_000_000;
-const SMALL_BUCKET_SIZE: u64 = 200_000;
+use crate::storage::Storage;
+
+pub const LARGE_BUCKET_SIZE: u64 = 20_000_000;
+pub const SMALL_BUCKET_SIZE: u64 = 200_000;
 
 pub fn artifact_path(base_directory: PathBuf, point: Point) -> PathBuf {
     let Point::Specific(slot, hash) = point else { panic!("must call artifact_subdirectory with a specific point") };
@@ -21,3 +29,100 @@ pub fn artifact_path(base_directory: PathBuf, point: Point) -> PathBuf {
 
     sub_directory.join(file)
 }
+
+/// Find where the header sits within a block body, so it can be sliced out without re-encoding it
+///
+/// Block bodies are an era tag followed by the block itself, whose first element is the header in every era
+pub fn header_range(body: &[u8]) -> Option<Range<usize>> {
+    let mut d = minicbor::Decoder::new(body);
+    d.array().ok()?;
+    d.u16().ok()?;
+    d.array().ok()?;
+    let start = d.position();
+    d.skip().ok()?;
+    Some(start..d.position())
+}
+
+/// Slice the header out of a block body, without re-encoding it
+pub fn extract_header(body: &[u8]) -> Option<&[u8]> {
+    header_range(body).map(|range| &body[range])
+}
+
+/// Parse a `{slot}-{hash}` artifact file name back into the point it was saved under
+pub fn parse_artifact_name(name: &str) -> Option<Point> {
+    let (slot, hash) = name.split_once('-')?;
+    let slot = slot.parse::<u64>().ok()?;
+    let hash = hex::decode(hash).ok()?;
+    if hash.len() != 32 {
+        return None;
+    }
+    Some(Point::Specific(slot, hash))
+}
+
+/// List the numeric subdirectories (i.e. buckets) of a directory, newest first, skipping any that start after `max_slot`
+pub fn buckets_before(directory: &Path, max_slot: u64) -> Vec<(u64, PathBuf)> {
+    let Ok(entries) = fs::read_dir(directory) else { return vec![] };
+    let mut buckets: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| Some((e.file_name().to_str()?.parse::<u64>().ok()?, e.path())))
+        .filter(|(start, _)| *start <= max_slot)
+        .collect();
+    buckets.sort_by_key(|(start, _)| Reverse(*start));
+    buckets
+}
+
+/// Pick points from an artifact store at exponentially growing distances back from the newest one
+///
+/// Much like the node does when looking for an intersection, this gives a handful of points that are
+/// dense near the tip, and sparse all the way back to the oldest thing we have stored.
+pub fn exponential_points(storage: &dyn Storage) -> Vec<Point> {
+    let mut points: Vec<Point> = vec![];
+    let Some(newest) = storage.before(u64::MAX) else { return points };
+    let tip = newest.slot_or_default();
+    points.push(newest);
+
+    let mut distance = 1u64;
+    while distance <= tip {
+        if let Some(point) = storage.before(tip - distance) {
+            if points.last() != Some(&point) {
+                points.push(point);
+            }
+        }
+        distance *= 2;
+    }
+
+    // Make sure the oldest artifact is included, so that we can intersect anywhere along the archive
+    if let Some(point) = storage.points().next() {
+        if points.last() != Some(&point) {
+            points.push(point);
+        }
+    }
+    points
+}
+
+/// The hash of the block before this one, or None if it is the first block of the chain
+pub fn previous_hash(header: &MultiEraHeader) -> Option<Vec<u8>> {
+    let previous = match header {
+        // The first epoch boundary block follows the genesis block, which we never store
+        MultiEraHeader::EpochBoundary(x) if x.consensus_data.epoch_id == 0 => None,
+        MultiEraHeader::EpochBoundary(x) => Some(x.prev_block),
+        MultiEraHeader::Byron(x) => Some(x.prev_block),
+        MultiEraHeader::AlonzoCompatible(x) => x.header_body.prev_hash,
+        MultiEraHeader::Babbage(x) => x.header_body.prev_hash,
+    };
+    previous.map(|h| h.to_vec())
+}
+
+/// The era tag a block is wrapped in: 0 for an epoch boundary block, 1 for any other byron block, 2 for shelley, and so on
+pub fn era_tag(body: &[u8]) -> Option<u16> {
+    let mut d = minicbor::Decoder::new(body);
+    d.array().and_then(|_| d.u16()).ok()
+}
+
+/// A point as JSON: `"origin"`, or its slot and hex hash
+pub fn point_json(point: &Point) -> Value {
+    match point {
+        Point::Origin => json!("origin"),
+        Point::Specific(slot, hash) => json!({ "slot": slot, "hash": hex::encode(hash) }),
+    }
+}
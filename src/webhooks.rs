@@ -0,0 +1,172 @@
+use std::{
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use pallas::network::miniprotocols::{chainsync::Tip, Point};
+use serde_json::{json, Value};
+
+use crate::utils::point_json;
+
+/// How many times to try delivering each notification to each webhook
+const WEBHOOK_ATTEMPTS: u32 = 5;
+/// How long to wait before the first retry; each one after waits twice as long
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long a webhook has to respond
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something about a relay worth telling someone about
+pub enum Notification {
+    /// We've caught up with the relay's tip
+    Tip,
+    /// The relay rolled us back this many blocks, more than we were asked to tolerate
+    Rollback { depth: u64 },
+    /// We haven't had a new block from the relay in this long
+    Stall { since: Duration },
+    /// The connection to the relay dropped
+    Disconnect,
+}
+
+impl Notification {
+    fn name(&self) -> &'static str {
+        match self {
+            Notification::Tip => "tip",
+            Notification::Rollback { .. } => "rollback",
+            Notification::Stall { .. } => "stall",
+            Notification::Disconnect => "disconnect",
+        }
+    }
+}
+
+/// POSTs a JSON notification to each webhook when a relay reaches its tip, rolls us back too far, stalls or
+/// disconnects
+///
+/// Notifications are delivered to each webhook in the background, in order, and retried a few times before they're
+/// given up on, so a slow or broken webhook never holds up slurping, or the other webhooks.
+#[derive(Default)]
+pub struct Webhooks {
+    senders: Vec<Sender<Arc<Value>>>,
+    /// The deepest rollback that isn't worth a notification
+    pub rollback_depth: u64,
+    /// How long a relay can go without giving us a block before it's reported as stalled
+    pub stall_interval: Option<Duration>,
+}
+
+impl Webhooks {
+    pub fn new(urls: Vec<String>, rollback_depth: u64, stall_interval: Duration) -> Self {
+        if urls.is_empty() {
+            return Webhooks::default();
+        }
+        let senders = urls
+            .into_iter()
+            .map(|url| {
+                let (sender, receiver) = mpsc::channel::<Arc<Value>>();
+                thread::spawn(move || {
+                    for payload in receiver {
+                        deliver(&url, &payload, WEBHOOK_RETRY_DELAY);
+                    }
+                });
+                sender
+            })
+            .collect();
+        Webhooks {
+            senders,
+            rollback_depth,
+            stall_interval: Some(stall_interval),
+        }
+    }
+
+    pub fn notify(&self, notification: Notification, relay: &str, point: Option<&Point>, tip: Option<&Tip>) {
+        if self.senders.is_empty() {
+            return;
+        }
+        let mut payload = json!({
+            "event": notification.name(),
+            "relay": relay,
+            "point": point.map(point_json),
+            "tip": tip.map(|tip| json!({ "point": point_json(&tip.0), "block_number": tip.1 })),
+            "at": SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        });
+        match notification {
+            Notification::Rollback { depth } => payload["depth"] = json!(depth),
+            Notification::Stall { since } => payload["seconds"] = json!(since.as_secs()),
+            Notification::Tip | Notification::Disconnect => {}
+        }
+        let payload = Arc::new(payload);
+        for sender in &self.senders {
+            // A delivery thread only stops if it panics, in which case there's nobody left to tell
+            let _ = sender.send(payload.clone());
+        }
+    }
+}
+
+/// POST a notification to a webhook, retrying after `retry_delay`, then twice as long each time after, and returning
+/// whether it got through
+fn deliver(url: &str, payload: &Value, retry_delay: Duration) -> bool {
+    let body = payload.to_string();
+    let mut delay = retry_delay;
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let result = ureq::post(url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .set("Content-Type", "application/json")
+            .send_string(&body);
+        match result {
+            Ok(_) => return true,
+            Err(e) if attempt < WEBHOOK_ATTEMPTS => {
+                log::warn!("unable to notify {} (attempt {} of {}), retrying in {:?}: {}", url, attempt, WEBHOOK_ATTEMPTS, delay, e);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(e) => log::error!("giving up notifying {} of a {} event: {}", url, payload["event"], e),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::webhook;
+
+    #[test]
+    fn each_notification_is_posted_as_json() {
+        let (url, received) = webhook(vec![]);
+        let webhooks = Webhooks::new(vec![url], 3, Duration::from_secs(60));
+        let point = Point::Specific(90, vec![1; 32]);
+        let tip = Tip(Point::Specific(100, vec![2; 32]), 7);
+        webhooks.notify(Notification::Tip, "relay:3001", Some(&point), Some(&tip));
+        webhooks.notify(Notification::Rollback { depth: 5 }, "relay:3001", Some(&point), None);
+        webhooks.notify(Notification::Stall { since: Duration::from_secs(90) }, "relay:3001", None, Some(&tip));
+        webhooks.notify(Notification::Disconnect, "relay:3001", None, None);
+
+        let mut payloads: Vec<Value> = (0..4).map(|_| received.recv_timeout(Duration::from_secs(10)).unwrap()).collect();
+        for payload in &mut payloads {
+            assert!(payload["at"].as_u64().unwrap() > 0);
+            payload.as_object_mut().unwrap().remove("at");
+        }
+        let point = json!({ "slot": 90, "hash": hex::encode([1; 32]) });
+        let tip = json!({ "point": { "slot": 100, "hash": hex::encode([2; 32]) }, "block_number": 7 });
+        assert_eq!(payloads, [
+            json!({ "event": "tip", "relay": "relay:3001", "point": point, "tip": tip }),
+            json!({ "event": "rollback", "relay": "relay:3001", "point": point, "tip": null, "depth": 5 }),
+            json!({ "event": "stall", "relay": "relay:3001", "point": null, "tip": tip, "seconds": 90 }),
+            json!({ "event": "disconnect", "relay": "relay:3001", "point": null, "tip": null }),
+        ]);
+    }
+
+    #[test]
+    fn deliveries_are_retried_and_then_given_up_on() {
+        let payload = json!({ "event": "tip" });
+        let (url, received) = webhook(vec![500, 503]);
+        assert!(deliver(&url, &payload, Duration::from_millis(10)));
+        assert_eq!(received.try_iter().count(), 3);
+
+        let (url, received) = webhook(vec![500; WEBHOOK_ATTEMPTS as usize + 1]);
+        assert!(!deliver(&url, &payload, Duration::from_millis(10)));
+        assert_eq!(received.try_iter().count(), WEBHOOK_ATTEMPTS as usize);
+    }
+}